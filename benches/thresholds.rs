@@ -0,0 +1,156 @@
+//! Insert and lookup cost across treeify thresholds and load factors, for
+//! picking the defaults in src/hashmap.rs:
+//!
+//!     cargo bench --bench thresholds
+//!
+//! The knobs are build-time constants, so each configuration rebuilds the
+//! crate with the `MAPS_*` overrides set and runs this bench again as a
+//! child, in its own target directory so the outer build isn't locked.
+
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::hint::black_box;
+use std::path::Path;
+use std::process::Command;
+use std::time::Instant;
+
+use maps::hashmap::HashMap;
+
+const CHILD: &str = "MAPS_THRESHOLDS_CHILD";
+const ENTRIES: u64 = 100_000;
+const COLLIDING: u64 = 2_000;
+const RUNS: usize = 3;
+
+/// (treeify, untreeify, load factor percent)
+const CONFIGS: &[(usize, usize, usize)] = &[
+    (4, 3, 75),
+    (8, 6, 50),
+    (8, 6, 75),
+    (8, 6, 100),
+    (16, 12, 75),
+];
+
+/// Keeps every key's hash distinct but clears the low 32 bits, so all keys
+/// share bucket 0 however far the table grows
+#[derive(Default)]
+struct OneBucket;
+
+struct OneBucketHasher(u64);
+
+impl Hasher for OneBucketHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = self.0.rotate_left(8) ^ b as u64;
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.0 = i << 32;
+    }
+}
+
+impl BuildHasher for OneBucket {
+    type Hasher = OneBucketHasher;
+
+    fn build_hasher(&self) -> OneBucketHasher {
+        OneBucketHasher(0)
+    }
+}
+
+fn xorshift(n: u64) -> Vec<u64> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    (0..n)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        })
+        .collect()
+}
+
+/// Best of `RUNS` ns per insert and per hit, building a fresh map each run
+fn measure<K, S>(keys: &[K], hasher: impl Fn() -> S) -> (f64, f64)
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher,
+{
+    let mut best = (f64::MAX, f64::MAX);
+    for _ in 0..RUNS {
+        let owned: Vec<K> = keys.to_vec();
+        let mut map = HashMap::with_hasher(hasher());
+        let start = Instant::now();
+        for (i, key) in owned.into_iter().enumerate() {
+            map.insert(key, i);
+        }
+        let insert = start.elapsed().as_nanos() as f64 / keys.len() as f64;
+
+        let start = Instant::now();
+        let mut sum = 0;
+        for key in keys {
+            sum += map.get(black_box(key)).unwrap();
+        }
+        black_box(sum);
+        let hit = start.elapsed().as_nanos() as f64 / keys.len() as f64;
+        best = (best.0.min(insert), best.1.min(hit));
+    }
+    best
+}
+
+/// Runs the three distributions in this build and prints them as one line
+fn child() {
+    let uniform = xorshift(ENTRIES);
+    let strings: Vec<String> = uniform.iter().map(|k| format!("key-{}", k)).collect();
+    let colliding: Vec<u64> = (0..COLLIDING).collect();
+
+    let cells = [
+        measure(&uniform, RandomState::new),
+        measure(&strings, RandomState::new),
+        measure(&colliding, || OneBucket),
+    ];
+    let line: Vec<String> = cells
+        .iter()
+        .map(|(insert, hit)| format!("{:>5.1} / {:<5.1}", insert, hit))
+        .collect();
+    println!("{}", line.join("  "));
+}
+
+fn main() {
+    if env::var_os(CHILD).is_some() {
+        return child();
+    }
+
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    println!(
+        "ns per insert / hit, best of {}; {} uniform u64 and String keys, {} keys in one bucket",
+        RUNS, ENTRIES, COLLIDING
+    );
+    println!();
+    println!("treeify/untreeify  load  uniform u64    String         all colliding");
+    for &(treeify, untreeify, load) in CONFIGS {
+        let out = Command::new(&cargo)
+            .args(["bench", "--quiet", "--bench", "thresholds"])
+            .current_dir(manifest_dir)
+            .env(CHILD, "1")
+            .env("CARGO_TARGET_DIR", manifest_dir.join("target/thresholds"))
+            .env("MAPS_TREEIFY_THRESHOLD", treeify.to_string())
+            .env("MAPS_UNTREEIFY_THRESHOLD", untreeify.to_string())
+            .env("MAPS_LOAD_FACTOR_PERCENT", load.to_string())
+            .output()
+            .expect("failed to run cargo");
+        assert!(
+            out.status.success(),
+            "child bench failed:\n{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        let row = stdout.lines().last().unwrap_or("").trim_end();
+        println!("{:>7}/{:<10} {:>3}%  {}", treeify, untreeify, load, row);
+    }
+}
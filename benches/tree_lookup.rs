@@ -0,0 +1,90 @@
+//! Lookup latency in a single tree bucket of 100k entries, for comparing
+//! builds with and without the `prefetch` feature:
+//!
+//!     cargo bench --bench tree_lookup
+//!     cargo bench --bench tree_lookup --features prefetch
+
+use std::hash::{BuildHasher, Hasher};
+use std::hint::black_box;
+use std::time::Instant;
+
+use maps::hashmap::HashMap;
+
+const ENTRIES: u64 = 100_000;
+const LOOKUPS: usize = 1_000_000;
+const RUNS: usize = 5;
+
+/// Keeps every key's hash distinct but clears the low 32 bits, so all keys
+/// share bucket 0 however far the table grows
+#[derive(Default)]
+struct OneBucket;
+
+struct OneBucketHasher(u64);
+
+impl Hasher for OneBucketHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = self.0.rotate_left(8) ^ b as u64;
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.0 = i << 32;
+    }
+}
+
+impl BuildHasher for OneBucket {
+    type Hasher = OneBucketHasher;
+
+    fn build_hasher(&self) -> OneBucketHasher {
+        OneBucketHasher(0)
+    }
+}
+
+fn main() {
+    let mut map = HashMap::with_hasher(OneBucket);
+    for k in 0..ENTRIES {
+        map.insert(k, k);
+    }
+    let stats = map.stats();
+    assert_eq!(stats.tree_buckets, 1);
+
+    // xorshift, so the walk down the tree differs from lookup to lookup
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let keys: Vec<u64> = (0..LOOKUPS)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % ENTRIES
+        })
+        .collect();
+
+    let mut best = f64::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let mut sum = 0;
+        for key in &keys {
+            sum += map.get(black_box(key)).unwrap();
+        }
+        black_box(sum);
+        best = best.min(start.elapsed().as_nanos() as f64 / LOOKUPS as f64);
+    }
+
+    println!(
+        "tree of {} entries, height {}, prefetch {}: {:.1} ns per lookup (best of {})",
+        ENTRIES,
+        stats.max_tree_height,
+        if cfg!(feature = "prefetch") {
+            "on"
+        } else {
+            "off"
+        },
+        best,
+        RUNS
+    );
+}
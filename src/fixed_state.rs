@@ -0,0 +1,64 @@
+use std::hash::{BuildHasher, Hasher};
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// splitmix64 finalizer
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Deterministic `BuildHasher` whose output depends only on its two seeds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FixedState {
+    k0: u64,
+    k1: u64,
+}
+
+impl FixedState {
+    pub fn with_seeds(k0: u64, k1: u64) -> Self {
+        Self { k0, k1 }
+    }
+
+    /// Derives both seeds from one `u64` with two splitmix64 steps, so e.g.
+    /// a shard index can pick a distinct but reproducible hasher
+    pub fn from_seed(seed: u64) -> Self {
+        let k0 = mix(seed.wrapping_add(GOLDEN_GAMMA));
+        let k1 = mix(seed.wrapping_add(GOLDEN_GAMMA.wrapping_mul(2)));
+        Self { k0, k1 }
+    }
+}
+
+impl BuildHasher for FixedState {
+    type Hasher = FixedHasher;
+
+    fn build_hasher(&self) -> FixedHasher {
+        FixedHasher {
+            state: FNV_OFFSET ^ self.k0,
+            k1: self.k1,
+        }
+    }
+}
+
+/// Seeded FNV-1a with a final avalanche so the low bits used for bucket
+/// indexing depend on every input byte
+#[derive(Debug, Clone)]
+pub struct FixedHasher {
+    state: u64,
+    k1: u64,
+}
+
+impl Hasher for FixedHasher {
+    fn finish(&self) -> u64 {
+        mix(self.state ^ self.k1)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.state = (self.state ^ b as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+}
@@ -1,38 +1,574 @@
 use std::borrow::Borrow;
+use std::cell::Cell;
 use std::collections::hash_map::RandomState;
+use std::collections::TryReserveError;
+use std::fmt;
 use std::hash::{BuildHasher, Hash, Hasher};
 use std::iter::FromIterator;
+use std::mem;
+use std::ops::{ControlFlow, Range};
+use std::ptr::NonNull;
+#[cfg(debug_assertions)]
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::avl_tree::AvlTree;
-use crate::linked_list::LinkedList;
+use crate::avl_tree::{self, AvlTree};
+use crate::fixed_state::FixedState;
+use crate::inline_map::{self, InlineMap};
+use crate::linked_list::{self, LinkedList};
+use crate::probes::{ProbeTotals, Probes};
 
 #[derive(Debug)]
-enum Entry<K, V> {
+pub(crate) enum Bucket<K, V> {
     ListEntry(LinkedList<K, V>),
-    #[allow(dead_code)]
     TreeEntry(AvlTree<K, V>),
     Empty,
 }
 
-impl<K, V> Default for Entry<K, V> {
+impl<K, V> Default for Bucket<K, V> {
     fn default() -> Self {
-        Entry::Empty
+        Bucket::Empty
+    }
+}
+
+impl<K, V> Bucket<K, V> {
+    /// Most entries a list bucket holds before it is turned into a tree
+    pub(crate) const TREEIFY_THRESHOLD: usize = TREEIFY_THRESHOLD;
+    /// A tree bucket shrinking below this many entries goes back to a list.
+    /// Kept under `TREEIFY_THRESHOLD` so a bucket hovering around one size
+    /// doesn't flip back and forth.
+    pub(crate) const UNTREEIFY_THRESHOLD: usize = UNTREEIFY_THRESHOLD;
+
+    /// Whether the bucket is `Empty` exactly when it holds no entries
+    fn is_consistent(&self) -> bool {
+        match self {
+            Bucket::Empty => true,
+            Bucket::ListEntry(list) => !list.is_empty(),
+            Bucket::TreeEntry(tree) => !tree.is_empty(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Bucket::ListEntry(list) => list.len(),
+            Bucket::TreeEntry(tree) => tree.len(),
+            Bucket::Empty => 0,
+        }
+    }
+}
+
+impl<K: Eq, V> Bucket<K, V> {
+    #[inline]
+    fn get<Q: ?Sized>(&self, hash: u64, key: &Q, probes: &mut Probes) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Eq,
+    {
+        match self {
+            Bucket::Empty => None,
+            Bucket::ListEntry(list) => list.get(key, probes),
+            Bucket::TreeEntry(tree) => tree.get(hash, key, probes),
+        }
+    }
+
+    fn replace_key(&mut self, hash: u64, key: K) -> Option<K> {
+        match self {
+            Bucket::Empty => None,
+            Bucket::ListEntry(list) => list.replace_key(key),
+            Bucket::TreeEntry(tree) => tree.replace_key(hash, key),
+        }
+    }
+
+    #[inline]
+    fn get_key_value<Q: ?Sized>(&self, hash: u64, key: &Q, probes: &mut Probes) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Eq,
+    {
+        match self {
+            Bucket::Empty => None,
+            Bucket::ListEntry(list) => list.get_key_value(key, probes),
+            Bucket::TreeEntry(tree) => tree.get_key_value(hash, key, probes),
+        }
+    }
+
+    #[inline]
+    fn get_mut<Q: ?Sized>(&mut self, hash: u64, key: &Q, probes: &mut Probes) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Eq,
+    {
+        match self {
+            Bucket::Empty => None,
+            Bucket::ListEntry(list) => list.get_mut(key, probes),
+            Bucket::TreeEntry(tree) => tree.get_mut(hash, key, probes),
+        }
+    }
+
+    #[inline]
+    fn find<F: FnMut(&K) -> bool>(&self, hash: u64, is_match: F) -> Option<(&K, &V)> {
+        match self {
+            Bucket::Empty => None,
+            Bucket::ListEntry(list) => list.find(hash, is_match),
+            Bucket::TreeEntry(tree) => tree.find(hash, is_match),
+        }
+    }
+
+    /// Inserts into the bucket, turning an empty one into a list
+    #[inline]
+    fn insert(&mut self, hash: u64, key: K, value: V) -> Option<V> {
+        match self {
+            Bucket::Empty => {
+                let mut list = LinkedList::new();
+                list.insert(hash, key, value);
+                *self = Bucket::ListEntry(list);
+                None
+            }
+            Bucket::ListEntry(list) => list.insert(hash, key, value),
+            Bucket::TreeEntry(tree) => tree.insert(hash, key, value),
+        }
+    }
+
+    /// Inserts entries with distinct keys, sorted by hash, that all belong
+    /// in this bucket, counting the new ones in `len`. Keys already here get
+    /// the batch's value. A list the batch pushes past `TREEIFY_THRESHOLD`
+    /// is turned into a tree once, with the new entries in it.
+    fn insert_batch(&mut self, batch: Vec<(u64, K, V)>, len: &mut usize) {
+        // update the keys already here before the bucket changes shape
+        let mut new = Vec::with_capacity(batch.len());
+        for (hash, key, value) in batch {
+            match self.get_mut(hash, &key, &mut Probes::default()) {
+                Some(slot) => *slot = value,
+                None => new.push((hash, key, value)),
+            }
+        }
+        if new.is_empty() {
+            return;
+        }
+
+        match self {
+            Bucket::TreeEntry(tree) => {
+                for (hash, key, value) in new {
+                    tree.insert(hash, key, value);
+                    *len += 1;
+                }
+            }
+            Bucket::ListEntry(list) if list.len() + new.len() <= Self::TREEIFY_THRESHOLD => {
+                for (hash, key, value) in new {
+                    list.insert(hash, key, value);
+                    *len += 1;
+                }
+            }
+            _ => {
+                let added = new.len();
+                let mut entries = match mem::take(self) {
+                    Bucket::ListEntry(list) => list.into_iter_hashed().collect(),
+                    _ => Vec::new(),
+                };
+                entries.extend(new);
+                entries.sort_by_key(|&(hash, _, _)| hash);
+                *self = if entries.len() > Self::TREEIFY_THRESHOLD {
+                    Bucket::TreeEntry(AvlTree::from_sorted(entries))
+                } else {
+                    Bucket::ListEntry(LinkedList::from_unique_sorted(entries))
+                };
+                *len += added;
+            }
+        }
+    }
+
+    /// Rebuilds the bucket from scratch, making a tree minimal height
+    fn rebuild(&mut self) {
+        *self = match mem::take(self) {
+            Bucket::ListEntry(list) => Bucket::ListEntry(LinkedList::from_unique_sorted(
+                list.into_iter_hashed().collect(),
+            )),
+            // in order, so already sorted by hash
+            Bucket::TreeEntry(tree) => {
+                Bucket::TreeEntry(AvlTree::from_sorted(tree.into_iter_hashed().collect()))
+            }
+            Bucket::Empty => Bucket::Empty,
+        };
+    }
+
+    /// Returns the value stored under `key`, inserting `f()` first if there
+    /// isn't one, along with whether `f` was called. The caller readies the
+    /// bucket with `HashMap::prepare_insert` first.
+    fn get_or_insert_with<F: FnOnce() -> V>(&mut self, hash: u64, key: K, f: F) -> (&mut V, bool) {
+        if let Bucket::Empty = self {
+            *self = Bucket::ListEntry(LinkedList::new());
+        }
+
+        let mut inserted = false;
+        let insert = || {
+            inserted = true;
+            f()
+        };
+        let value = match self {
+            Bucket::ListEntry(list) => list.get_or_insert_with(hash, key, insert),
+            Bucket::TreeEntry(tree) => tree.get_or_insert_with(hash, key, insert),
+            Bucket::Empty => unreachable!(),
+        };
+        (value, inserted)
+    }
+
+    /// Removes `key` from the bucket, resetting it to `Empty` if that was
+    /// its last entry and to a list if a tree got small enough
+    fn remove_entry<Q: ?Sized>(&mut self, hash: u64, key: &Q, probes: &mut Probes) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Eq,
+    {
+        let removed = match self {
+            Bucket::ListEntry(list) => list.remove_entry_reporting_empty(key, probes),
+            Bucket::TreeEntry(tree) => tree.remove_entry_reporting_empty(hash, key, probes),
+            Bucket::Empty => return None,
+        };
+        self.settle_after_remove(removed)
+    }
+
+    /// Removes the entry stored under `hash` whose value is at `value`,
+    /// telling entries apart by address so no key is compared, then tidies
+    /// the bucket like `remove_entry`
+    fn remove_value(&mut self, hash: u64, value: *const V) -> Option<(K, V)> {
+        let removed = match self {
+            Bucket::ListEntry(list) => list.remove_value_reporting_empty(value),
+            Bucket::TreeEntry(tree) => tree.remove_value_reporting_empty(hash, value),
+            Bucket::Empty => return None,
+        };
+        self.settle_after_remove(removed)
+    }
+
+    fn settle_after_remove(&mut self, (res, now_empty): (Option<(K, V)>, bool)) -> Option<(K, V)> {
+        if now_empty {
+            *self = Bucket::Empty;
+        } else if res.is_some() {
+            self.untreeify_if_small();
+        }
+        res
+    }
+
+    /// Turns a list bucket into a tree if it already holds
+    /// `TREEIFY_THRESHOLD` entries, so one more can go in. The entries are
+    /// sorted by hash and linked into a balanced tree in one go rather than
+    /// inserted one at a time.
+    fn treeify_if_full(&mut self) {
+        if matches!(self, Bucket::ListEntry(list) if list.len() >= Self::TREEIFY_THRESHOLD) {
+            self.treeify();
+        }
+    }
+
+    /// Turns the bucket into a tree whatever its size, an empty one
+    /// included, for maps that were told to expect collisions
+    fn treeify(&mut self) {
+        *self = match mem::take(self) {
+            Bucket::ListEntry(list) => {
+                let mut entries: Vec<_> = list.into_iter_hashed().collect();
+                // already sorted, but stable so equal hashes keep their order
+                entries.sort_by_key(|&(hash, _, _)| hash);
+                Bucket::TreeEntry(AvlTree::from_sorted(entries))
+            }
+            Bucket::TreeEntry(tree) => Bucket::TreeEntry(tree),
+            Bucket::Empty => Bucket::TreeEntry(AvlTree::new()),
+        };
+    }
+
+    fn untreeify_if_small(&mut self) {
+        match self {
+            // an AVL tree of height 4 has at least 7 nodes, so the O(1)
+            // height check skips counting big trees
+            Bucket::TreeEntry(tree)
+                if tree.height() <= 3 && tree.len() < Self::UNTREEIFY_THRESHOLD => {}
+            _ => return,
+        }
+
+        if let Bucket::TreeEntry(tree) = mem::take(self) {
+            *self = Bucket::ListEntry(LinkedList::from_unique_sorted(
+                tree.into_iter_hashed().collect(),
+            ));
+        }
+    }
+
+    /// Calls `f` on every entry in place, pushing whether to keep it onto
+    /// `keep` in the order `remove_marked` takes them. Stops at the first
+    /// `Break`, leaving the rest unmarked. Nothing is moved, so a panic in
+    /// `f` leaves the bucket as it was.
+    fn mark<F>(&mut self, keep: &mut Vec<bool>, f: &mut F) -> ControlFlow<()>
+    where
+        F: FnMut(&K, &mut V) -> ControlFlow<(), bool>,
+    {
+        let mut visit = |(k, v): (&K, &mut V)| {
+            keep.push(f(k, v)?);
+            ControlFlow::Continue(())
+        };
+        match self {
+            Bucket::Empty => ControlFlow::Continue(()),
+            Bucket::ListEntry(list) => list.iter_mut().try_for_each(&mut visit),
+            Bucket::TreeEntry(tree) => tree.iter_mut().try_for_each(&mut visit),
+        }
+    }
+
+    /// Takes the bucket apart, handing each entry `keep` marks false to
+    /// `removed` and putting the rest back. Entries past the end of `keep`
+    /// are kept. `len` drops as each entry goes, so if `removed` or a
+    /// destructor panics it still matches what the rebuilt bucket holds.
+    fn remove_marked<I, R>(&mut self, keep: I, len: &mut usize, mut removed: R)
+    where
+        I: IntoIterator<Item = bool>,
+        R: FnMut(K, V),
+    {
+        let (entries, tree): (Vec<_>, _) = match mem::take(self) {
+            Bucket::Empty => return,
+            Bucket::ListEntry(list) => (list.into_iter_hashed().collect(), false),
+            Bucket::TreeEntry(tree) => (tree.into_iter_hashed().collect(), true),
+        };
+
+        let mut guard = RebuildGuard {
+            bucket: self,
+            tree,
+            kept: Vec::with_capacity(entries.len()),
+            rest: entries.into_iter(),
+        };
+        let mut keep = keep.into_iter();
+        for (hash, k, v) in guard.rest.by_ref() {
+            if keep.next().unwrap_or(true) {
+                guard.kept.push((hash, k, v));
+            } else {
+                *len -= 1;
+                removed(k, v);
+            }
+        }
+    }
+}
+
+/// Puts a bucket taken apart by `Bucket::remove_marked` back together when
+/// dropped, so a panic partway through doesn't lose the entries not yet
+/// looked at
+struct RebuildGuard<'a, K: Eq, V> {
+    bucket: &'a mut Bucket<K, V>,
+    tree: bool,
+    kept: Vec<(u64, K, V)>,
+    rest: std::vec::IntoIter<(u64, K, V)>,
+}
+
+impl<K: Eq, V> Drop for RebuildGuard<'_, K, V> {
+    fn drop(&mut self) {
+        // empty unless unwinding
+        self.kept.extend(self.rest.by_ref());
+
+        let kept = mem::take(&mut self.kept);
+        *self.bucket = if kept.is_empty() {
+            Bucket::Empty
+        } else if self.tree {
+            Bucket::TreeEntry(AvlTree::from_sorted(kept))
+        } else {
+            Bucket::ListEntry(LinkedList::from_unique_sorted(kept))
+        };
+        self.bucket.untreeify_if_small();
     }
 }
 
-const LOAD_FACTOR: f64 = 0.75;
+// The thresholds and the load factor can be overridden at build time, e.g.
+// `MAPS_TREEIFY_THRESHOLD=16 cargo build`, which is how benches/thresholds.rs
+// sweeps them. One run of that sweep, ns per insert / hit, 100k uniform u64
+// and String keys, 2k keys sharing one bucket, best of 3:
+//
+//   treeify/untreeify  load  uniform u64    String         all colliding
+//         4/3           75%  287.7 / 92.2   364.6 / 135.4  189.6 / 46.9
+//         8/6           50%  231.9 / 90.3   320.1 / 134.6  182.3 / 48.0
+//         8/6           75%  283.5 / 94.2   361.1 / 130.7  192.5 / 51.3
+//         8/6          100%  181.8 / 73.0   250.7 / 132.2  152.9 / 48.5
+//        16/12          75%  347.1 / 112.4  406.8 / 148.6  179.2 / 45.2
+//
+// Runs differ by 10-15%, more than the thresholds move anything: uniform
+// keys almost never fill a bucket to 4, and a colliding bucket is a tree
+// whichever threshold it crossed. So 8/6 stays, trees being the bigger
+// nodes. The insert column mostly counts resizes (100k entries fit 131072
+// buckets at 100% but need 262144 at 75%). 100% cost String hits up to 20%
+// in other runs and 50% bought nothing on hits, so 75% stays too.
+const TREEIFY_THRESHOLD: usize = tuned(option_env!("MAPS_TREEIFY_THRESHOLD"), 8);
+const UNTREEIFY_THRESHOLD: usize = tuned(option_env!("MAPS_UNTREEIFY_THRESHOLD"), 6);
+// the load factor as a percentage, so thresholds are exact integers
+const LOAD_FACTOR_NUM: usize = tuned(option_env!("MAPS_LOAD_FACTOR_PERCENT"), 75);
+const LOAD_FACTOR_DEN: usize = 100;
 const DEFAULT_CAPACITY: usize = 16;
 
+const _: () = assert!(
+    0 < UNTREEIFY_THRESHOLD && UNTREEIFY_THRESHOLD < TREEIFY_THRESHOLD,
+    "MAPS_UNTREEIFY_THRESHOLD must be positive and below MAPS_TREEIFY_THRESHOLD"
+);
+const _: () = assert!(
+    0 < LOAD_FACTOR_NUM && LOAD_FACTOR_NUM <= LOAD_FACTOR_DEN,
+    "MAPS_LOAD_FACTOR_PERCENT must be in 1..=100"
+);
+
+/// Parses a build-time override, or gives `default` when it isn't set
+const fn tuned(var: Option<&str>, default: usize) -> usize {
+    let bytes = match var {
+        Some(var) => var.as_bytes(),
+        None => return default,
+    };
+    assert!(!bytes.is_empty(), "empty MAPS_* override");
+    let mut n = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(
+            bytes[i].is_ascii_digit(),
+            "MAPS_* overrides must be decimal"
+        );
+        n = n * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    n
+}
+
+/// Largest power of two table of `Bucket<K, V>` that fits in an allocation
+fn max_capacity<K, V>() -> usize {
+    let max = isize::MAX as usize / mem::size_of::<Bucket<K, V>>().max(1);
+    1 << (usize::BITS - 1 - max.leading_zeros())
+}
+
+/// Number of entries at which a table of `cap` buckets grows, i.e.
+/// `cap * LOAD_FACTOR_NUM / LOAD_FACTOR_DEN` rounded down without overflowing
+fn resize_threshold(cap: usize) -> usize {
+    cap / LOAD_FACTOR_DEN * LOAD_FACTOR_NUM
+        + cap % LOAD_FACTOR_DEN * LOAD_FACTOR_NUM / LOAD_FACTOR_DEN
+}
+
+/// Smallest table that holds `len` entries without growing, 0 for few
+/// enough to keep inline
+fn capacity_for(len: usize) -> usize {
+    if len <= inline_map::CAPACITY {
+        return 0;
+    }
+
+    let mut cap = 1;
+    while cap > 0 && len >= resize_threshold(cap) {
+        cap <<= 1;
+    }
+    cap
+}
+
+/// Allocates a table of `cap` empty buckets, reporting failure instead of
+/// aborting
+fn try_alloc_table<K, V>(cap: usize) -> Result<Box<[Bucket<K, V>]>, TryReserveError> {
+    let mut v = Vec::new();
+    v.try_reserve_exact(cap)?;
+    v.resize_with(cap, Default::default);
+    Ok(v.into_boxed_slice())
+}
+
+fn grown_capacity(cap: usize, max_cap: usize) -> usize {
+    assert!(cap < max_cap, "map at maximum capacity");
+    cap << 1
+}
+
+/// Merges the entries with equal keys in a list sorted by hash, keeping the
+/// first key and the last value like repeated inserts do. Only entries with
+/// equal hashes are compared.
+fn dedup_sorted<K: Eq, V>(entries: Vec<(u64, K, V)>) -> Vec<(u64, K, V)> {
+    let mut deduped: Vec<(u64, K, V)> = Vec::with_capacity(entries.len());
+    // where the entries sharing the current hash start in `deduped`
+    let mut run = 0;
+    for (hash, key, value) in entries {
+        if let Some(&(last, _, _)) = deduped.last() {
+            if last != hash {
+                run = deduped.len();
+            }
+        }
+
+        match deduped[run..].iter_mut().find(|(_, k, _)| *k == key) {
+            Some(entry) => entry.2 = value,
+            None => deduped.push((hash, key, value)),
+        }
+    }
+    deduped
+}
+
+/// Kind of bucket a removal looked in, reported by
+/// `HashMap::remove_instrumented`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovedFrom {
+    List,
+    Tree,
+    /// The key's bucket was empty
+    Empty,
+    /// The map was small enough to keep its entries inline
+    Inline,
+}
+
+/// Lookup cost counted by a map built `with_probe_stats`, returned by
+/// `HashMap::probe_stats`
+#[cfg(feature = "probe-stats")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProbeStats {
+    /// Entries compared against the looked up key, over all lookups
+    pub total_probes: u64,
+    /// `get`s and `remove`s counted, hits and misses alike
+    pub total_lookups: u64,
+}
+
+/// Shape of a single bucket, yielded by `HashMap::iter_buckets`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BucketKind {
+    Empty,
+    List { len: usize },
+    Tree { len: usize, height: usize },
+}
+
+/// Snapshot of the table's shape returned by `HashMap::stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapStats {
+    pub len: usize,
+    pub capacity: usize,
+    /// `len / capacity`, or 0 for an unallocated table
+    pub load: f64,
+    pub empty_buckets: usize,
+    pub list_buckets: usize,
+    pub tree_buckets: usize,
+    /// Length of the longest list bucket
+    pub longest_chain: usize,
+    /// Height of the tallest tree bucket
+    pub max_tree_height: usize,
+}
+
+/// Observer called with `(old_cap, new_cap)` after every resize
+struct ResizeHook(Box<dyn FnMut(usize, usize)>);
+
+impl fmt::Debug for ResizeHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResizeHook")
+    }
+}
+
 #[derive(Debug)]
 pub struct HashMap<K, V, S = RandomState> {
-    table: Box<[Entry<K, V>]>,
+    table: Box<[Bucket<K, V>]>,
+    /// Holds the entries while there are no more than
+    /// `inline_map::CAPACITY` of them, so small maps don't allocate. The
+    /// table is only allocated once one more goes in, and this is empty
+    /// whenever it is.
+    inline: InlineMap<K, V>,
     hash_builder: S,
     len: usize,
+    resize_hook: Option<ResizeHook>,
+    /// Bucket index and hash of the last key found by a lookup, if the map
+    /// was built `with_lookup_cache`
+    lookup_cache: Option<Cell<Option<(usize, u64)>>>,
+    /// Set by `reserve_colliding`, makes every bucket a tree from its
+    /// first entry
+    expect_collisions: bool,
+    /// Running lookup cost, if the map was built `with_probe_stats`
+    probe_stats: ProbeTotals,
+    /// Bumped by everything that adds, removes or moves entries, so
+    /// iterators can catch the map changing under them. Borrowing rules out doing that from
+    /// safe code; this guards the unsafe bucket internals.
+    #[cfg(debug_assertions)]
+    generation: AtomicUsize,
 }
 
 impl<K, V> HashMap<K, V, RandomState> {
     pub fn new() -> Self {
-        Self::with_capacity_and_hasher(DEFAULT_CAPACITY, RandomState::new())
+        Self::with_hasher(RandomState::new())
     }
 
     pub fn with_capacity(cap: usize) -> Self {
@@ -40,17 +576,33 @@ impl<K, V> HashMap<K, V, RandomState> {
     }
 }
 
+impl<K, V> HashMap<K, V, FixedState> {
+    /// Map hashing with a `FixedState` built from the given seeds, so key
+    /// placement is reproducible across runs
+    pub fn with_seed(k0: u64, k1: u64) -> Self {
+        Self::with_hasher(FixedState::with_seeds(k0, k1))
+    }
+
+    /// Map hashing with a `FixedState` derived from a single seed, see
+    /// `FixedState::from_seed`
+    pub fn seeded(seed: u64) -> Self {
+        Self::with_hasher(FixedState::from_seed(seed))
+    }
+}
+
 impl<K, V, S> HashMap<K, V, S> {
+    /// Doesn't allocate until the first insert
     pub fn with_hasher(hash_builder: S) -> Self {
-        Self::with_capacity_and_hasher(DEFAULT_CAPACITY, hash_builder)
+        Self::with_capacity_and_hasher(0, hash_builder)
     }
 
-    // TODO resizing guarantees
+    /// Sizes the table so `cap` entries can be inserted without it growing.
+    /// `capacity()` reports buckets, so it will be larger than `cap`, or 0
+    /// if that few entries are kept inline.
     pub fn with_capacity_and_hasher(cap: usize, hash_builder: S) -> Self {
-        let mut capacity = 1;
-        while capacity < cap {
-            capacity <<= 1;
-        }
+        // a zero capacity table is allocated by the insert that outgrows
+        // the inline entries
+        let capacity = capacity_for(cap);
 
         let mut v = Vec::new();
         for _ in 0..capacity {
@@ -60,11 +612,45 @@ impl<K, V, S> HashMap<K, V, S> {
 
         Self {
             table,
+            inline: InlineMap::new(),
             hash_builder,
             len: 0,
+            resize_hook: None,
+            lookup_cache: None,
+            expect_collisions: false,
+            probe_stats: ProbeTotals::default(),
+            #[cfg(debug_assertions)]
+            generation: AtomicUsize::new(0),
         }
     }
 
+    /// Makes lookups remember where they last found a key, so looking up
+    /// the same key again checks that bucket before hashing anything. Every
+    /// lookup pays for the extra check, so this only helps workloads that
+    /// keep coming back to the same key.
+    pub fn with_lookup_cache(mut self) -> Self {
+        self.lookup_cache = Some(Cell::new(None));
+        self
+    }
+
+    /// Makes `get`, `get_mut` and `remove` and the methods built on them
+    /// count how many entries each lookup compares the key against, read
+    /// back with `probe_stats`. The count is taken during the lookup
+    /// itself. Only there with the `probe-stats` feature, so other builds
+    /// don't pay for the check.
+    #[cfg(feature = "probe-stats")]
+    pub fn with_probe_stats(mut self) -> Self {
+        self.probe_stats = ProbeTotals::enabled();
+        self
+    }
+
+    /// Lookup cost counted so far, all zeros unless the map was built
+    /// `with_probe_stats`
+    #[cfg(feature = "probe-stats")]
+    pub fn probe_stats(&self) -> ProbeStats {
+        self.probe_stats.get()
+    }
+
     pub fn len(&self) -> usize {
         self.len
     }
@@ -72,6 +658,289 @@ impl<K, V, S> HashMap<K, V, S> {
     pub fn is_empty(&self) -> bool {
         self.len == 0
     }
+
+    /// Number of buckets in the table, 0 while the entries are kept inline
+    pub fn capacity(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Borrows every entry mutably, in no particular order, so values can
+    /// be updated without hashing their keys again
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut {
+            table: self.table.iter_mut(),
+            // empty once there's a table
+            bucket: BucketIterMut::InlineIter(self.inline.iter_mut()),
+            remaining: self.len,
+            #[cfg(debug_assertions)]
+            generation: Generation::of(&self.generation),
+        }
+    }
+
+    /// Splits the table into `n` ranges of adjacent buckets, as even as
+    /// the capacity allows, each borrowing its entries mutably. No entry is
+    /// in two ranges, so each can be worked on independently of the others
+    /// while the map stays borrowed. Ranges are empty when `n` is past the
+    /// capacity.
+    ///
+    /// Entries kept inline, before there is a table to split, all go in the
+    /// first range.
+    ///
+    /// The ranges can't be moved to other threads yet: tree buckets link
+    /// their nodes through `NonNull`, which makes `Bucket`, and so
+    /// `BucketSliceMut`, `!Send`.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is 0.
+    pub fn split_at_buckets(&mut self, n: usize) -> Vec<BucketSliceMut<'_, K, V>> {
+        assert!(n > 0, "split_at_buckets into 0 ranges");
+        #[cfg(debug_assertions)]
+        let generation = &self.generation;
+        let cap = self.table.len();
+        let mut rest = &mut self.table[..];
+        let mut inline = Some(&mut self.inline);
+        let mut slices = Vec::with_capacity(n);
+        for i in 0..n {
+            // bucket i * cap / n starts range i
+            let size = (i + 1) * cap / n - i * cap / n;
+            let (buckets, tail) = rest.split_at_mut(size);
+            rest = tail;
+            let inline = inline.take();
+            slices.push(BucketSliceMut {
+                len: buckets.iter().map(Bucket::len).sum::<usize>()
+                    + inline.as_ref().map_or(0, |inline| inline.len()),
+                inline,
+                buckets,
+                #[cfg(debug_assertions)]
+                generation,
+            });
+        }
+        slices
+    }
+
+    pub fn into_keys(self) -> IntoKeys<K, V> {
+        IntoKeys {
+            inner: self.into_iter(),
+        }
+    }
+
+    pub fn into_values(self) -> IntoValues<K, V> {
+        IntoValues {
+            inner: self.into_iter(),
+        }
+    }
+
+    pub fn values_mut(&mut self) -> ValuesMut<'_, K, V> {
+        ValuesMut {
+            inner: self.iter_mut(),
+        }
+    }
+
+    /// Borrows every entry, in no particular order. The walk only depends
+    /// on what is stored, bucket by bucket, so iterating a map that hasn't
+    /// been modified in between always gives the same order.
+    ///
+    /// Within a bucket, list or tree, entries come in ascending hash order,
+    /// and entries with equal hashes in the order they were inserted. So
+    /// with a fixed table size and hasher the order is fully determined by
+    /// the keys and the order they went in. Entries kept inline, before the
+    /// table is allocated, come in the order they went in.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            table: &self.table,
+            index: 0,
+            // empty once there's a table
+            bucket: BucketIter::InlineIter(self.inline.iter()),
+            remaining: self.len,
+            #[cfg(debug_assertions)]
+            generation: Generation::of(&self.generation),
+        }
+    }
+
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys { inner: self.iter() }
+    }
+
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values { inner: self.iter() }
+    }
+
+    /// Empties the map, keeping its table allocated
+    pub fn clear(&mut self) {
+        // dropping the drain frees every node on the way
+        self.drain();
+    }
+
+    /// Empties the map, dropping entries in ascending key order so values
+    /// with side effects in `Drop` are torn down deterministically. The
+    /// table stays allocated.
+    pub fn clear_ordered(&mut self)
+    where
+        K: Ord,
+    {
+        let mut entries = self.take_all();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        for entry in entries {
+            drop(entry);
+        }
+    }
+
+    /// Consumes the map, yielding its entries in ascending key order
+    pub fn into_iter_sorted(self) -> IntoIterSorted<K, V>
+    where
+        K: Ord,
+    {
+        let mut entries: Vec<_> = self.into_iter().collect();
+        // keys are unique, so an unstable sort gives the same order
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        IntoIterSorted {
+            entries: entries.into_iter(),
+        }
+    }
+
+    /// Hands every bucket to `f` for maintenance passes that rebuild them,
+    /// e.g. switching a bucket between list and tree form. `f` must leave
+    /// each bucket holding exactly the keys it had, or `len` and lookups
+    /// stop matching the contents. Entries kept inline aren't in a bucket,
+    /// so `f` never sees them.
+    // nothing in the crate runs such a pass yet
+    #[allow(dead_code)]
+    pub(crate) fn for_each_bucket_mut<F: FnMut(&mut Bucket<K, V>)>(&mut self, f: F) {
+        self.note_change();
+        self.table.iter_mut().for_each(f);
+    }
+
+    /// Walks the table once yielding each bucket's index and shape, e.g. for
+    /// drawing a histogram of the table
+    pub fn iter_buckets(&self) -> impl Iterator<Item = (usize, BucketKind)> + '_ {
+        self.table.iter().enumerate().map(|(i, entry)| {
+            let kind = match entry {
+                Bucket::Empty => BucketKind::Empty,
+                Bucket::ListEntry(list) => BucketKind::List { len: list.len() },
+                Bucket::TreeEntry(tree) => BucketKind::Tree {
+                    len: tree.len(),
+                    height: tree.height(),
+                },
+            };
+            (i, kind)
+        })
+    }
+
+    /// Panics unless every bucket is `Empty` exactly when it holds no
+    /// entries, no list is past `TREEIFY_THRESHOLD`, nothing is left inline
+    /// once there is a table, and `len` matches the number of entries
+    /// actually stored
+    #[cfg(test)]
+    fn check_invariants(&self) {
+        let mut stored = 0;
+        for (entry, (i, kind)) in self.table.iter().zip(self.iter_buckets()) {
+            assert!(entry.is_consistent(), "bucket {} is empty but not Empty", i);
+            if let BucketKind::List { len } = kind {
+                assert!(
+                    len <= Bucket::<K, V>::TREEIFY_THRESHOLD,
+                    "list bucket {} should have been treeified",
+                    i
+                );
+            }
+            stored += match kind {
+                BucketKind::Empty => 0,
+                BucketKind::List { len } | BucketKind::Tree { len, .. } => len,
+            };
+        }
+        assert!(
+            self.table.is_empty() || self.inline.len() == 0,
+            "entries left inline next to the table"
+        );
+        stored += self.inline.len();
+        assert_eq!(stored, self.len, "len doesn't match the stored entries");
+    }
+
+    /// Collects bucket statistics in a single pass over the table
+    pub fn stats(&self) -> MapStats {
+        let mut stats = MapStats {
+            len: self.len,
+            capacity: self.table.len(),
+            load: 0.0,
+            empty_buckets: 0,
+            list_buckets: 0,
+            tree_buckets: 0,
+            longest_chain: 0,
+            max_tree_height: 0,
+        };
+        if !self.table.is_empty() {
+            stats.load = self.len as f64 / self.table.len() as f64;
+        }
+
+        for entry in self.table.iter() {
+            match entry {
+                Bucket::ListEntry(list) => {
+                    stats.list_buckets += 1;
+                    stats.longest_chain = stats.longest_chain.max(list.len());
+                }
+                Bucket::TreeEntry(tree) => {
+                    stats.tree_buckets += 1;
+                    stats.max_tree_height = stats.max_tree_height.max(tree.height());
+                }
+                Bucket::Empty => stats.empty_buckets += 1,
+            }
+        }
+
+        stats
+    }
+
+    /// Clones every entry into a `Vec` sized to `len`, in no particular order
+    pub fn to_vec(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let mut entries = Vec::with_capacity(self.len);
+        entries.extend(self.iter().map(|(k, v)| (k.clone(), v.clone())));
+        entries
+    }
+
+    /// Moves every entry out into a `Vec`, leaving the map empty but with its
+    /// table still allocated
+    pub fn take_all(&mut self) -> Vec<(K, V)> {
+        self.drain().collect()
+    }
+
+    /// Moves every entry out, leaving the map empty but with its table still
+    /// allocated. Dropping the `Drain` early still empties the map.
+    pub fn drain(&mut self) -> Drain<'_, K, V> {
+        self.note_change();
+        let remaining = mem::replace(&mut self.len, 0);
+        Drain {
+            table: self.table.iter_mut(),
+            bucket: BucketIntoIter::InlineIter(mem::take(&mut self.inline).into_iter()),
+            remaining,
+        }
+    }
+
+    /// Registers a hook called with `(old_cap, new_cap)` whenever the table
+    /// is reallocated, including the first allocation of a lazy map and
+    /// `shrink_to_fit`. It is purely observational and replaces any previous
+    /// hook.
+    pub fn set_resize_hook(&mut self, hook: Box<dyn FnMut(usize, usize)>) {
+        self.resize_hook = Some(ResizeHook(hook));
+    }
+
+    /// Called by everything that adds, removes or moves entries, including
+    /// handing out something that can, like a `Drain` or a `FoundEntry`.
+    /// Clears the lookup cache,
+    /// though a stale entry would only cost a miss since a hit is always
+    /// checked against the keys actually in the bucket, and in debug builds
+    /// moves on the generation iterators check.
+    fn note_change(&mut self) {
+        if let Some(cache) = &self.lookup_cache {
+            cache.set(None);
+        }
+        #[cfg(debug_assertions)]
+        {
+            *self.generation.get_mut() += 1;
+        }
+    }
 }
 
 impl<K, V, S> HashMap<K, V, S>
@@ -89,282 +958,5191 @@ where
         hasher.finish()
     }
 
-    fn hash_index<Q: ?Sized>(&self, hash: u64) -> usize
-    where
-        Q: Hash + Eq,
-        K: Borrow<Q>,
-    {
+    fn hash_index(&self, hash: u64) -> usize {
         hash as usize & (self.table.len() - 1)
     }
 
-    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
-        let ret = self.insert_into_table(key, value);
-        if ret.is_none() {
-            self.len += 1;
-        }
-
-        if self.len() >= (LOAD_FACTOR * self.table.len() as f64) as usize {
-            self.resize();
-        }
-
-        ret
-    }
-
-    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
+    /// Hash of `key` and the bucket it belongs in, or `None` if the table
+    /// hasn't been allocated yet
+    #[inline]
+    fn bucket<Q: ?Sized>(&self, key: &Q) -> Option<(u64, &Bucket<K, V>)>
     where
         Q: Hash + Eq,
         K: Borrow<Q>,
     {
-        self.get_key_value(key).is_some()
+        if self.table.is_empty() {
+            return None;
+        }
+
+        let hash = self.hash(key);
+        Some((hash, &self.table[self.hash_index(hash)]))
     }
 
-    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+    #[inline]
+    fn bucket_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<(u64, &mut Bucket<K, V>)>
     where
         Q: Hash + Eq,
         K: Borrow<Q>,
     {
+        if self.table.is_empty() {
+            return None;
+        }
+
         let hash = self.hash(key);
         let index = self.hash_index(hash);
-
-        match &mut self.table[index] {
-            Entry::ListEntry(list) => list.get_mut(key),
-            Entry::TreeEntry(tree) => tree.get_mut(hash, key),
-            Entry::Empty => None,
-        }
+        Some((hash, &mut self.table[index]))
     }
 
-    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V>
+    /// Looks `key` up in the bucket the last successful lookup found its key
+    /// in, without hashing it. Returns the bucket's index and the hash it
+    /// was found under.
+    fn cached_lookup<Q: ?Sized>(&self, key: &Q, probes: &mut Probes) -> Option<(usize, u64, &K, &V)>
     where
-        Q: Hash + Eq,
+        Q: Eq,
         K: Borrow<Q>,
     {
-        self.get_key_value(key).map(|(_k, v)| v)
+        let (index, hash, bucket) = self.cached_bucket()?;
+        let (k, v) = bucket.get_key_value(hash, key, probes)?;
+        Some((index, hash, k, v))
     }
 
-    pub fn get_key_value<Q: ?Sized>(&self, key: &Q) -> Option<(&K, &V)>
-    where
-        Q: Hash + Eq,
-        K: Borrow<Q>,
-    {
-        let hash = self.hash(key);
-        let index = self.hash_index(hash);
+    /// Index, hash and bucket of the last key a lookup found, if the map was
+    /// built `with_lookup_cache`. A tree only compares keys stored under
+    /// `hash`, and anything a list finds is in the right bucket, so a hit in
+    /// it is the key's real entry.
+    #[inline]
+    fn cached_bucket(&self) -> Option<(usize, u64, &Bucket<K, V>)> {
+        let (index, hash) = self.lookup_cache.as_ref()?.get()?;
+        Some((index, hash, self.table.get(index)?))
+    }
 
-        match &self.table[index] {
-            Entry::ListEntry(list) => list.get_key_value(key),
-            Entry::TreeEntry(tree) => tree.get_key_value(hash, key),
-            Entry::Empty => None,
+    fn remember_lookup(&self, hash: u64) {
+        if let Some(cache) = &self.lookup_cache {
+            cache.set(Some((self.hash_index(hash), hash)));
         }
     }
 
-    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
+    /// Hash the map's hasher produces for `key`
+    pub fn hash_of<Q: ?Sized>(&self, key: &Q) -> u64
     where
         Q: Hash + Eq,
         K: Borrow<Q>,
     {
-        self.remove_entry(key).map(|(_k, v)| v)
+        self.hash(key)
     }
 
-    pub fn remove_entry<Q: ?Sized>(&mut self, key: &Q) -> Option<(K, V)>
+    /// Index of the bucket `key` maps to in the current table, or `None`
+    /// while the entries are kept inline
+    pub fn bucket_index_of<Q: ?Sized>(&self, key: &Q) -> Option<usize>
     where
         Q: Hash + Eq,
         K: Borrow<Q>,
     {
-        let hash = self.hash(key);
-        let index = self.hash_index(hash);
+        if self.table.is_empty() {
+            return None;
+        }
+
+        Some(self.hash_index(self.hash(key)))
+    }
+
+    /// # Panics
+    ///
+    /// Panics with "map at maximum capacity" if the table would have to grow
+    /// past `max_capacity`, which caps the map at the load factor times that
+    /// many entries.
+    #[inline]
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.checked_insert(key, value).0
+    }
+
+    /// Like `insert`, but also reports whether the table was reallocated
+    /// during this insert, so callers can account for the O(n) spike
+    pub fn checked_insert(&mut self, key: K, value: V) -> (Option<V>, bool) {
+        let hash = self.hash(&key);
+        let mut resized = false;
+        // the table is allocated by the first key that doesn't fit inline
+        if self.table.is_empty()
+            && self.len == inline_map::CAPACITY
+            && self.inline.position(&key, &mut Probes::default()).is_none()
+        {
+            self.resize_to(DEFAULT_CAPACITY);
+            resized = true;
+        }
+
+        let ret = self.insert_with_hash(hash, key, value);
+        if ret.is_none() {
+            self.len += 1;
+        }
+
+        if !self.table.is_empty() && self.len() >= resize_threshold(self.table.len()) {
+            self.resize();
+            resized = true;
+        }
+
+        (ret, resized)
+    }
+
+    /// Inserts without ever reallocating the table. A new key that would
+    /// push the map past its load factor (or past the inline entries of an
+    /// unallocated table) is handed back as `Err`; replacing an existing
+    /// key always succeeds.
+    pub fn try_insert_no_grow(&mut self, key: K, value: V) -> Result<Option<V>, (K, V)> {
+        if let Some(existing) = self.get_mut(&key) {
+            return Ok(Some(mem::replace(existing, value)));
+        }
+
+        if !self.fits_one_more() {
+            return Err((key, value));
+        }
+
+        self.insert_into_table(key, value);
+        self.len += 1;
+        Ok(None)
+    }
+
+    /// Returns the value stored under `key`, inserting `f()` first if there
+    /// isn't one. The table is grown before looking up the key, so it may
+    /// grow one insert early when the key is already present. A key already
+    /// kept inline never allocates the table.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: K, f: F) -> &mut V {
+        // grow first so no resize can move the value after we hand it out
+        if !self.table.is_empty() || self.inline.position(&key, &mut Probes::default()).is_none() {
+            self.grow_for_one_more();
+        }
+        self.note_change();
+
+        let hash = self.hash(&key);
+        let (value, inserted) = if self.table.is_empty() {
+            self.inline.get_or_insert_with(hash, key, f)
+        } else {
+            let index = self.hash_index(hash);
+            self.prepare_insert(index);
+            self.table[index].get_or_insert_with(hash, key, f)
+        };
+        if inserted {
+            self.len += 1;
+        }
+
+        value
+    }
+
+    /// Gets the entry for `key` to inspect or fill in place. The key is
+    /// hashed once. Only inserting into a vacant entry grows the table, so
+    /// looking at a key that is already present never does.
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, S> {
+        let hash = self.hash(&key);
+        // no bucket to remember while the entries are inline
+        let (index, found) = if self.table.is_empty() {
+            (0, self.inline.get(&key, &mut Probes::default()))
+        } else {
+            let index = self.hash_index(hash);
+            (
+                index,
+                self.table[index].get(hash, &key, &mut Probes::default()),
+            )
+        };
+        if found.is_some() {
+            Entry::Occupied(OccupiedEntry {
+                map: self,
+                index,
+                hash,
+                key,
+            })
+        } else {
+            Entry::Vacant(VacantEntry {
+                map: self,
+                index,
+                hash,
+                key,
+            })
+        }
+    }
+
+    /// Whether one more entry fits inline, or in the table under the load
+    /// factor
+    fn fits_one_more(&self) -> bool {
+        if self.table.is_empty() {
+            self.len < inline_map::CAPACITY
+        } else {
+            self.len + 1 < resize_threshold(self.table.len())
+        }
+    }
+
+    /// Allocates or grows the table unless one more entry already fits
+    fn grow_for_one_more(&mut self) {
+        if self.fits_one_more() {
+            return;
+        }
+
+        if self.table.is_empty() {
+            self.resize_to(DEFAULT_CAPACITY);
+        } else {
+            self.resize();
+        }
+    }
+
+    /// Inserts like `insert`, also handing back a reference to the stored
+    /// value. Like `get_or_insert_with`, any growth happens before the value
+    /// is placed, so the reference stays valid.
+    pub fn insert_and_get(&mut self, key: K, value: V) -> (&mut V, Option<V>) {
+        let mut value = Some(value);
+        let slot = self.get_or_insert_with(key, || value.take().unwrap());
+        // still there if the key was already present
+        let old = value.map(|new| mem::replace(slot, new));
+        (slot, old)
+    }
+
+    /// Inserts `on_insert()` if `key` is absent, otherwise calls `on_update`
+    /// on the value already there. The key is only hashed once.
+    pub fn insert_or_update<I, U>(&mut self, key: K, on_insert: I, on_update: U)
+    where
+        I: FnOnce() -> V,
+        U: FnOnce(&mut V),
+    {
+        let mut inserted = false;
+        let value = self.get_or_insert_with(key, || {
+            inserted = true;
+            on_insert()
+        });
+        if !inserted {
+            on_update(value);
+        }
+    }
+
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
+    where
+        Q: Hash + Eq,
+        K: Borrow<Q>,
+    {
+        self.get_key_value(key).is_some()
+    }
+
+    #[inline]
+    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        Q: Hash + Eq,
+        K: Borrow<Q>,
+    {
+        let mut probes = Probes::default();
+        if let Some((index, hash, _, _)) = self.cached_lookup(key, &mut probes) {
+            self.probe_stats.record(probes);
+            return self.table[index].get_mut(hash, key, &mut Probes::default());
+        }
+
+        if self.table.is_empty() {
+            let value = self.inline.get_mut(key, &mut probes);
+            self.probe_stats.record(probes);
+            return value;
+        }
+        let hash = self.hash(key);
+        let index = self.hash_index(hash);
+        // only `table` is borrowed by the value, so the stats and the cache
+        // can still be updated
+        let value = self.table[index].get_mut(hash, key, &mut probes);
+        self.probe_stats.record(probes);
+        let value = value?;
+        if let Some(cache) = &self.lookup_cache {
+            cache.set(Some((index, hash)));
+        }
+        Some(value)
+    }
+
+    /// Looks up every key in `keys` at once, for when how many there are is
+    /// only known at runtime. Panics if the same key appears twice, since
+    /// that would hand out two mutable references to one value.
+    pub fn get_each_mut<Q: ?Sized>(&mut self, keys: &[&Q]) -> Vec<Option<&mut V>>
+    where
+        Q: Hash + Eq,
+        K: Borrow<Q>,
+    {
+        for (i, key) in keys.iter().enumerate() {
+            assert!(
+                !keys[..i].contains(key),
+                "duplicate key passed to get_each_mut"
+            );
+        }
+
+        // inline values sit side by side, so looking up a later key would
+        // borrow the ones already handed out. Split them up front instead.
+        if self.table.is_empty() {
+            let slots: Vec<_> = keys
+                .iter()
+                .map(|key| {
+                    let mut probes = Probes::default();
+                    let slot = self.inline.position(*key, &mut probes);
+                    self.probe_stats.record(probes);
+                    slot
+                })
+                .collect();
+            let mut values: Vec<_> = self.inline.values_mut().into_iter().map(Some).collect();
+            return slots.into_iter().map(|slot| values[slot?].take()).collect();
+        }
+
+        // neither bucket kind borrows the values it walks past on a lookup,
+        // only the links between entries, so resolving a later key leaves
+        // the pointers already collected valid
+        let values: Vec<_> = keys
+            .iter()
+            .map(|key| self.get_mut(*key).map(|value| value as *mut V))
+            .collect();
+
+        // distinct keys point at distinct values, and the map stays
+        // mutably borrowed for as long as the references live
+        values
+            .into_iter()
+            .map(|value| value.map(|value| unsafe { &mut *value }))
+            .collect()
+    }
+
+    #[inline]
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V>
+    where
+        Q: Hash + Eq,
+        K: Borrow<Q>,
+    {
+        let mut probes = Probes::default();
+        let value = self.get_probed(key, &mut probes);
+        self.probe_stats.record(probes);
+        value
+    }
+
+    #[inline]
+    fn get_probed<Q: ?Sized>(&self, key: &Q, probes: &mut Probes) -> Option<&V>
+    where
+        Q: Hash + Eq,
+        K: Borrow<Q>,
+    {
+        if self.table.is_empty() {
+            return self.inline.get(key, probes);
+        }
+
+        if let Some(value) = self
+            .cached_bucket()
+            .and_then(|(_, hash, bucket)| bucket.get(hash, key, probes))
+        {
+            return Some(value);
+        }
+
+        let (hash, bucket) = self.bucket(key)?;
+        let value = bucket.get(hash, key, probes)?;
+        self.remember_lookup(hash);
+        Some(value)
+    }
+
+    #[inline]
+    pub fn get_key_value<Q: ?Sized>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        Q: Hash + Eq,
+        K: Borrow<Q>,
+    {
+        let mut probes = Probes::default();
+        let found = self.get_key_value_probed(key, &mut probes);
+        self.probe_stats.record(probes);
+        found
+    }
+
+    #[inline]
+    fn get_key_value_probed<Q: ?Sized>(&self, key: &Q, probes: &mut Probes) -> Option<(&K, &V)>
+    where
+        Q: Hash + Eq,
+        K: Borrow<Q>,
+    {
+        if self.table.is_empty() {
+            return self.inline.get_key_value(key, probes);
+        }
+
+        if let Some((_, _, k, v)) = self.cached_lookup(key, probes) {
+            return Some((k, v));
+        }
+
+        let (hash, bucket) = self.bucket(key)?;
+        let found = bucket.get_key_value(hash, key, probes)?;
+        self.remember_lookup(hash);
+        Some(found)
+    }
+
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        Q: Hash + Eq,
+        K: Borrow<Q>,
+    {
+        self.remove_entry(key).map(|(_k, v)| v)
+    }
+
+    /// A tree bucket left with fewer than `UNTREEIFY_THRESHOLD` entries is
+    /// turned back into a list by the same call, from the tree's in-order
+    /// entries
+    pub fn remove_entry<Q: ?Sized>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        Q: Hash + Eq,
+        K: Borrow<Q>,
+    {
+        self.remove_instrumented(key).0
+    }
+
+    /// `remove_entry` that also reports which kind of bucket the key was
+    /// looked up in, for measuring how removals split between lists and
+    /// trees
+    pub fn remove_instrumented<Q: ?Sized>(&mut self, key: &Q) -> (Option<(K, V)>, RemovedFrom)
+    where
+        Q: Hash + Eq,
+        K: Borrow<Q>,
+    {
+        self.note_change();
+        let mut probes = Probes::default();
+        if self.table.is_empty() {
+            let res = self.inline.remove_entry(key, &mut probes);
+            self.probe_stats.record(probes);
+            if res.is_some() {
+                self.len -= 1;
+            }
+            return (res, RemovedFrom::Inline);
+        }
+
+        let (hash, bucket) = match self.bucket_mut(key) {
+            Some(found) => found,
+            None => {
+                self.probe_stats.record(probes);
+                return (None, RemovedFrom::Empty);
+            }
+        };
+
+        let kind = match bucket {
+            Bucket::Empty => RemovedFrom::Empty,
+            Bucket::ListEntry(_) => RemovedFrom::List,
+            Bucket::TreeEntry(_) => RemovedFrom::Tree,
+        };
+        let res = bucket.remove_entry(hash, key, &mut probes);
+        debug_assert!(bucket.is_consistent(), "emptied bucket wasn't reset");
+        self.probe_stats.record(probes);
+        if res.is_some() {
+            self.len -= 1;
+        }
+        (res, kind)
+    }
+
+    /// Read-only lookups with a precomputed hash or custom key matching
+    pub fn raw_entry(&self) -> RawEntryBuilder<'_, K, V, S> {
+        RawEntryBuilder { map: self }
+    }
+
+    /// Locates `key` once and returns a handle that can read, update or
+    /// remove its entry without hashing the key or searching the bucket
+    /// again
+    pub fn find_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<FoundEntry<'_, K, V>>
+    where
+        Q: Hash + Eq,
+        K: Borrow<Q>,
+    {
+        // the entry can be removed through the handle
+        self.note_change();
+        if self.table.is_empty() {
+            let index = self.inline.position(key, &mut Probes::default())?;
+            return Some(FoundEntry {
+                home: Home::Inline {
+                    inline: &mut self.inline,
+                    index,
+                },
+                len: &mut self.len,
+            });
+        }
+
+        let hash = self.hash(key);
+        let index = self.hash_index(hash);
+        let bucket = &mut self.table[index];
+        let value = NonNull::from(bucket.get_mut(hash, key, &mut Probes::default())?);
+        Some(FoundEntry {
+            home: Home::Bucket {
+                bucket,
+                hash,
+                value,
+            },
+            len: &mut self.len,
+        })
+    }
+
+    /// Copies the map into one using `hash_builder`, e.g. to move to a
+    /// different hashing scheme. Every key is hashed again and placed from
+    /// scratch.
+    pub fn rehash_with<S2: BuildHasher>(&self, hash_builder: S2) -> HashMap<K, V, S2>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let mut map = HashMap::with_capacity_and_hasher(self.len, hash_builder);
+        for (k, v) in self.iter() {
+            map.insert(k.clone(), v.clone());
+        }
+        map
+    }
+
+    /// Moves every entry of `other` into `self`, calling `combine` with the
+    /// existing value and the incoming one when a key is already present
+    pub fn merge_with<F>(&mut self, mut other: HashMap<K, V, S>, mut combine: F)
+    where
+        F: FnMut(&K, &mut V, V),
+    {
+        for (k, v) in other.take_all() {
+            match self.get_mut(&k) {
+                Some(existing) => combine(&k, existing, v),
+                None => {
+                    self.insert(k, v);
+                }
+            }
+        }
+    }
+
+    /// Copies every pair of `pairs` into the map, reserving room for all of
+    /// them up front. Later pairs win over earlier ones with the same key.
+    pub fn extend_from_slice(&mut self, pairs: &[(K, V)])
+    where
+        K: Copy,
+        V: Copy,
+    {
+        self.reserve(pairs.len());
+        for &(k, v) in pairs {
+            self.insert(k, v);
+        }
+    }
+
+    /// Moves the entries matching `pred` into `dest`, keeping the rest in
+    /// `self` at its current capacity. `pred` sees every entry in place
+    /// before anything moves, so `dest` can be reserved for exactly the
+    /// matches; if `pred` panics, neither map changes. Kept entries aren't
+    /// hashed again, moved ones are hashed once with `dest`'s hasher.
+    ///
+    /// # Panics
+    ///
+    /// Panics with "map at maximum capacity" if `dest` can't grow to fit
+    /// the matches, see `try_drain_filter_into`.
+    pub fn drain_filter_into<F>(&mut self, pred: F, dest: &mut HashMap<K, V, S>)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let (keep, marked) = self.mark_unless(pred);
+        dest.reserve(keep.iter().filter(|&&keep| !keep).count());
+        self.move_marked(&keep, marked, dest);
+    }
+
+    /// `drain_filter_into`, reporting failure to reserve room in `dest`
+    /// instead of panicking. Neither map changes if it fails.
+    pub fn try_drain_filter_into<F>(
+        &mut self,
+        pred: F,
+        dest: &mut HashMap<K, V, S>,
+    ) -> Result<(), TryReserveError>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let (keep, marked) = self.mark_unless(pred);
+        dest.try_reserve_exact(keep.iter().filter(|&&keep| !keep).count())?;
+        self.move_marked(&keep, marked, dest);
+        Ok(())
+    }
+
+    /// One keep flag per entry, false where `pred` matches, and the index
+    /// and flags of each bucket with some to move. The inline entries'
+    /// flags lead. Nothing is moved.
+    fn mark_unless<F>(&mut self, mut pred: F) -> (Vec<bool>, Vec<(usize, Range<usize>)>)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let mut unless = |k: &K, v: &mut V| ControlFlow::Continue(!pred(k, v));
+        let mut keep = Vec::with_capacity(self.len);
+        // the inline entries' flags come first, ahead of any bucket's
+        let (flags, _) = self.inline.mark(&mut unless);
+        keep.extend_from_slice(&flags[..self.inline.len()]);
+        let mut marked = Vec::new();
+        for (i, bucket) in self.table.iter_mut().enumerate() {
+            let start = keep.len();
+            let _ = bucket.mark(&mut keep, &mut unless);
+            if keep[start..].contains(&false) {
+                marked.push((i, start..keep.len()));
+            }
+        }
+        (keep, marked)
+    }
+
+    /// Moves the entries `mark_unless` flagged into `dest`, which must
+    /// already have room for them
+    fn move_marked(
+        &mut self,
+        keep: &[bool],
+        marked: Vec<(usize, Range<usize>)>,
+        dest: &mut HashMap<K, V, S>,
+    ) {
+        if keep.contains(&false) {
+            self.note_change();
+        }
+        // reserved by the caller, so dest won't resize
+        let mut insert = |k, v| {
+            dest.insert(k, v);
+        };
+        let inline = self.inline.len();
+        self.inline
+            .remove_marked(keep[..inline].iter().copied(), &mut self.len, &mut insert);
+        for (i, range) in marked {
+            self.table[i].remove_marked(keep[range].iter().copied(), &mut self.len, &mut insert);
+        }
+    }
+
+    /// Keeps only the entries for which `f` returns true, resetting buckets
+    /// it empties. A panic in `f` or in a removed entry's destructor leaves
+    /// the map consistent, see `retain_while`.
+    ///
+    /// `f` is called once per entry, in iteration order, with the entry's
+    /// own key and value; it may update the value in place. It must not
+    /// insert into or remove from the map, which safe code can't do while
+    /// `retain` holds it anyway. Debug builds panic if the map changes under
+    /// `f` rather than walk buckets that have moved.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        self.retain_while(|k, v| ControlFlow::Continue(f(k, v)));
+    }
+
+    /// Like `retain`, but `f` can return `Break` to stop early, keeping the
+    /// entry it was called on and every entry it hasn't reached yet.
+    /// Only buckets that lose an entry are rebuilt, so stopping early also
+    /// skips the work for the rest of the table. If `f` panics, the entries
+    /// it already rejected in earlier buckets are gone and everything else
+    /// stays, with `len` matching.
+    pub fn retain_while<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &mut V) -> ControlFlow<(), bool>,
+    {
+        // before taking the generation `f` is checked against
+        self.note_change();
+        #[cfg(debug_assertions)]
+        let generation = Generation::of(&self.generation);
+        let mut checked = |k: &K, v: &mut V| {
+            let flow = f(k, v);
+            #[cfg(debug_assertions)]
+            generation.check();
+            flow
+        };
+        // empty once there's a table
+        let (keep, flow) = self.inline.mark(&mut checked);
+        if keep.contains(&false) {
+            self.inline
+                .remove_marked(keep.iter().copied(), &mut self.len, |_, _| {});
+        }
+        if flow.is_break() {
+            return;
+        }
+
+        let mut keep = Vec::new();
+        for bucket in self.table.iter_mut() {
+            keep.clear();
+            let flow = bucket.mark(&mut keep, &mut checked);
+            if keep.contains(&false) {
+                bucket.remove_marked(keep.drain(..), &mut self.len, |_, _| {});
+            }
+            if flow.is_break() {
+                break;
+            }
+        }
+    }
+
+    /// Shrinks the table to the smallest power of two that holds the current
+    /// entries without immediately growing again. The size only depends on
+    /// `len`, so a hasher that piles every key into one bucket can't make it
+    /// loop or shrink further.
+    pub fn shrink_to_fit(&mut self) {
+        // a map small enough to keep its entries inline gives its table
+        // back entirely
+        let new_cap = capacity_for(self.len);
+        if new_cap < self.table.len() {
+            self.resize_to(new_cap);
+        }
+    }
+
+    /// Rebuilds every bucket in place, so trees that churn left taller than
+    /// needed become minimal height again. Unlike `shrink_to_fit` the table
+    /// isn't reallocated and nothing is hashed; capacity and `len` stay the
+    /// same.
+    pub fn compact(&mut self) {
+        self.note_change();
+        for bucket in self.table.iter_mut() {
+            bucket.rebuild();
+        }
+    }
+
+    /// Makes room for `additional` more entries, so inserting that many new
+    /// keys won't grow the table
+    ///
+    /// # Panics
+    ///
+    /// Panics with "map at maximum capacity" if no table is large enough.
+    pub fn reserve(&mut self, additional: usize) {
+        let needed = self
+            .len
+            .checked_add(additional)
+            .expect("map at maximum capacity");
+        // capacity_for wraps around to 0 when no power of two is big enough,
+        // as well as for entries that fit inline
+        let new_cap = capacity_for(needed);
+        assert!(
+            (new_cap > 0 || needed <= inline_map::CAPACITY) && new_cap <= max_capacity::<K, V>(),
+            "map at maximum capacity"
+        );
+
+        if new_cap > self.table.len() {
+            self.resize_to(new_cap);
+        }
+    }
+
+    /// Advanced: `reserve`, for a workload whose keys are known to pile up
+    /// in a few buckets, e.g. under a weak hasher. From now on every bucket
+    /// is a tree from its first entry, and lists already in the table are
+    /// turned into trees here, so a big load doesn't build lists only to
+    /// convert them a few entries later. Lookups in buckets that hold just
+    /// a few keys get a little slower. A removal can still turn a small
+    /// tree back into a list; the next insert into it makes it a tree again.
+    pub fn reserve_colliding(&mut self, additional: usize) {
+        self.reserve(additional);
+        self.expect_collisions = true;
+        self.note_change();
+        for bucket in self.table.iter_mut() {
+            if let Bucket::ListEntry(_) = bucket {
+                bucket.treeify();
+            }
+        }
+    }
+
+    /// Makes room for `additional` more entries without going over the load
+    /// factor. Unlike growing on insert, the new table is only as big as
+    /// needed. If it can't be allocated the map is left as it was.
+    pub fn try_reserve_exact(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        if additional == 0 {
+            return Ok(());
+        }
+
+        // capacity_for wraps around to 0 when no power of two is big enough,
+        // and asking for usize::MAX buckets makes the allocation report it
+        let new_cap = match self.len.checked_add(additional) {
+            Some(needed) if needed <= inline_map::CAPACITY => 0,
+            Some(needed) => match capacity_for(needed) {
+                0 => usize::MAX,
+                cap => cap,
+            },
+            None => usize::MAX,
+        };
+        if new_cap <= self.table.len() {
+            return Ok(());
+        }
+
+        self.replace_table(try_alloc_table(new_cap)?);
+        Ok(())
+    }
+
+    /// Clones the map into a table of the same capacity, reporting failure
+    /// to allocate the table instead of aborting. The entries themselves
+    /// are still allocated node by node, which can't fail gracefully. The
+    /// resize hook isn't carried over.
+    pub fn try_clone(&self) -> Result<Self, TryReserveError>
+    where
+        K: Clone,
+        V: Clone,
+        S: Clone,
+    {
+        self.try_clone_with_capacity(self.table.len())
+    }
+
+    /// `try_clone` into a table of `cap` buckets, which must be a power of
+    /// two no smaller than the current table
+    fn try_clone_with_capacity(&self, cap: usize) -> Result<Self, TryReserveError>
+    where
+        K: Clone,
+        V: Clone,
+        S: Clone,
+    {
+        let mut map = HashMap {
+            table: try_alloc_table(cap)?,
+            inline: InlineMap::new(),
+            hash_builder: self.hash_builder.clone(),
+            len: 0,
+            resize_hook: None,
+            lookup_cache: self.lookup_cache.as_ref().map(|_| Cell::new(None)),
+            expect_collisions: self.expect_collisions,
+            probe_stats: self.probe_stats.cleared(),
+            #[cfg(debug_assertions)]
+            generation: AtomicUsize::new(0),
+        };
+        // reuse the stored hashes, as `replace_table` does, so the hasher
+        // isn't run again. At least as many buckets, so no resize check.
+        for (hash, k, v) in self.inline.iter_hashed() {
+            map.insert_with_hash(hash, k.clone(), v.clone());
+        }
+        for bucket in self.table.iter() {
+            match bucket {
+                Bucket::ListEntry(list) => {
+                    for (hash, k, v) in list.iter_hashed() {
+                        map.insert_with_hash(hash, k.clone(), v.clone());
+                    }
+                }
+                Bucket::TreeEntry(tree) => {
+                    for (hash, k, v) in tree.iter_hashed() {
+                        map.insert_with_hash(hash, k.clone(), v.clone());
+                    }
+                }
+                Bucket::Empty => {}
+            }
+        }
+        map.len = self.len;
+
+        Ok(map)
+    }
+
+    fn resize(&mut self) {
+        // new capacity is twice as large
+        let new_cap = grown_capacity(self.table.len(), max_capacity::<K, V>());
+        self.resize_to(new_cap);
+    }
+
+    fn resize_to(&mut self, new_cap: usize) {
+        let mut v = Vec::new();
+        for _ in 0..new_cap {
+            v.push(Default::default());
+        }
+
+        self.replace_table(v.into_boxed_slice());
+    }
+
+    /// Moves every entry into `table`, which must be empty. An empty
+    /// `table` takes the entries back inline, so there must be few enough.
+    fn replace_table(&mut self, table: Box<[Bucket<K, V>]>) {
+        let old_cap = self.table.len();
+        let new_cap = table.len();
+
+        self.note_change();
+
+        // Swap in new table size
+        let mut old_table = table;
+        std::mem::swap(&mut self.table, &mut old_table);
+
+        // empty unless the table is being allocated
+        for (hash, k, v) in mem::take(&mut self.inline).into_iter_hashed() {
+            self.insert_with_hash(hash, k, v);
+        }
+
+        // by value iterator. Both bucket kinds keep each key's hash, so the
+        // keys aren't hashed again. Every new bucket starts out `Empty` and
+        // fills up through the same list then tree path as a plain insert,
+        // so it ends up whichever kind its own size calls for, regardless of
+        // what the old bucket was.
+        for entry in Vec::from(old_table) {
+            match entry {
+                Bucket::ListEntry(list) => {
+                    for (hash, k, v) in list.into_iter_hashed() {
+                        self.insert_with_hash(hash, k, v);
+                    }
+                }
+                Bucket::TreeEntry(tree) => {
+                    for (hash, k, v) in tree.into_iter_hashed() {
+                        self.insert_with_hash(hash, k, v);
+                    }
+                }
+                Bucket::Empty => {}
+            }
+        }
+        // insert_with_hash never checks the load, so moving the entries
+        // can't have started another resize
+        debug_assert_eq!(self.table.len(), new_cap);
+
+        if let Some(ResizeHook(hook)) = &mut self.resize_hook {
+            hook(old_cap, new_cap);
+        }
+    }
+
+    #[inline]
+    fn insert_into_table(&mut self, key: K, value: V) -> Option<V> {
+        let hash = self.hash(&key);
+        self.insert_with_hash(hash, key, value)
+    }
+
+    /// Inserts already hashed entries as one batch. The table is sized
+    /// once, and each bucket the batch touches is filled in one go, so a
+    /// bucket pushed past `TREEIFY_THRESHOLD` becomes a tree straight from
+    /// `AvlTree::from_sorted` instead of partway through. Of equal keys the
+    /// first key and the last value are kept, as with repeated `insert`s.
+    fn insert_batch(&mut self, mut entries: Vec<(u64, K, V)>) {
+        // stable, so equal keys keep the order they came in
+        entries.sort_by_key(|&(hash, _, _)| hash);
+        let mut entries = dedup_sorted(entries);
+        if entries.is_empty() {
+            return;
+        }
+
+        // grow to the same table repeated inserts would end up with
+        let new = entries
+            .iter()
+            .filter(|(hash, key, _)| {
+                let mut probes = Probes::default();
+                if self.table.is_empty() {
+                    self.inline.get(key, &mut probes).is_none()
+                } else {
+                    self.table[self.hash_index(*hash)]
+                        .get(*hash, key, &mut probes)
+                        .is_none()
+                }
+            })
+            .count();
+        if self.table.is_empty() {
+            if self.len + new <= inline_map::CAPACITY {
+                for (hash, key, value) in entries {
+                    if self.insert_with_hash(hash, key, value).is_none() {
+                        self.len += 1;
+                    }
+                }
+                return;
+            }
+            self.resize_to(DEFAULT_CAPACITY);
+        }
+        self.reserve(new);
+        self.note_change();
+
+        // still sorted by hash within each bucket
+        entries.sort_by_key(|&(hash, _, _)| self.hash_index(hash));
+        let mut group = Vec::new();
+        let mut entries = entries.into_iter().peekable();
+        while let Some(entry) = entries.next() {
+            let index = self.hash_index(entry.0);
+            group.push(entry);
+            let last_in_bucket = match entries.peek() {
+                Some(&(next, _, _)) => self.hash_index(next) != index,
+                None => true,
+            };
+
+            if last_in_bucket {
+                if self.expect_collisions {
+                    self.table[index].treeify();
+                }
+                self.table[index].insert_batch(mem::take(&mut group), &mut self.len);
+            }
+        }
+    }
+
+    /// Inserts under an already known `hash`. Never looks at the load or
+    /// resizes, which `replace_table` relies on while moving entries. Without
+    /// a table the entry goes inline, which must have room for a new key.
+    #[inline]
+    fn insert_with_hash(&mut self, hash: u64, key: K, value: V) -> Option<V> {
+        self.note_change();
+        if self.table.is_empty() {
+            return self.inline.insert(hash, key, value);
+        }
+
+        let index = self.hash_index(hash);
+        self.prepare_insert(index);
+        self.table[index].insert(hash, key, value)
+    }
+
+    /// Readies the bucket at `index` for a new entry. A full list becomes a
+    /// tree, and after `reserve_colliding` every bucket is made one.
+    #[inline]
+    fn prepare_insert(&mut self, index: usize) {
+        if self.expect_collisions {
+            self.table[index].treeify();
+        } else {
+            self.table[index].treeify_if_full();
+        }
+    }
+}
+
+/// `&str` lookups for `String` keyed maps, without spelling out `as_str`
+impl<V, S: BuildHasher> HashMap<String, V, S> {
+    pub fn get_str(&self, key: &str) -> Option<&V> {
+        self.get(key)
+    }
+
+    pub fn contains_str(&self, key: &str) -> bool {
+        self.contains_key(key)
+    }
+
+    pub fn remove_str(&mut self, key: &str) -> Option<V> {
+        self.remove(key)
+    }
+}
+
+/// Builder returned by `HashMap::raw_entry`
+pub struct RawEntryBuilder<'a, K, V, S> {
+    map: &'a HashMap<K, V, S>,
+}
+
+impl<'a, K, V, S> RawEntryBuilder<'a, K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn from_key<Q: ?Sized>(self, key: &Q) -> Option<(&'a K, &'a V)>
+    where
+        Q: Hash + Eq,
+        K: Borrow<Q>,
+    {
+        self.map.get_key_value(key)
+    }
+
+    /// Looks up `key` using `hash` as its hash, which must be what the map's
+    /// hasher gives for it (see `HashMap::hash_of`)
+    pub fn from_key_hashed_nocheck<Q: ?Sized>(self, hash: u64, key: &Q) -> Option<(&'a K, &'a V)>
+    where
+        Q: Eq,
+        K: Borrow<Q>,
+    {
+        self.from_hash(hash, |k| k.borrow() == key)
+    }
+
+    /// Finds the entry stored under `hash` whose key satisfies `is_match`
+    pub fn from_hash<F>(self, hash: u64, is_match: F) -> Option<(&'a K, &'a V)>
+    where
+        F: FnMut(&K) -> bool,
+    {
+        let map = self.map;
+        if map.table.is_empty() {
+            return map.inline.find(hash, is_match);
+        }
+
+        map.table[map.hash_index(hash)].find(hash, is_match)
+    }
+}
+
+/// An entry known to be in the map, returned by `HashMap::find_mut`. It
+/// points straight at the entry's value, so nothing it does compares keys.
+pub struct FoundEntry<'a, K, V> {
+    home: Home<'a, K, V>,
+    len: &'a mut usize,
+}
+
+/// Where a `FoundEntry`'s entry is stored
+enum Home<'a, K, V> {
+    Bucket {
+        bucket: &'a mut Bucket<K, V>,
+        hash: u64,
+        /// Inside one of `bucket`'s nodes, which stay put while the bucket
+        /// is borrowed and only `remove_entry` changes it
+        value: NonNull<V>,
+    },
+    /// The inline entries sit in the map itself, so a pointer to one would
+    /// be invalidated by borrowing `inline`; the slot is kept instead
+    Inline {
+        inline: &'a mut InlineMap<K, V>,
+        index: usize,
+    },
+}
+
+impl<'a, K: Eq, V> FoundEntry<'a, K, V> {
+    pub fn get(&self) -> &V {
+        match &self.home {
+            // points at a live value, borrowed through `self`
+            Home::Bucket { value, .. } => unsafe { value.as_ref() },
+            Home::Inline { inline, index } => inline.value_at(*index),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        match &mut self.home {
+            // points at a live value, borrowed through `self`
+            Home::Bucket { value, .. } => unsafe { value.as_mut() },
+            Home::Inline { inline, index } => inline.value_at_mut(*index),
+        }
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        match self.home {
+            // points at a live value, and the bucket stays borrowed for 'a
+            Home::Bucket { value, .. } => unsafe { &mut *value.as_ptr() },
+            Home::Inline { inline, index } => inline.value_at_mut(index),
+        }
+    }
+
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    /// Unlinks the entry's node, picked out by the value's address, or
+    /// takes it out of its inline slot
+    pub fn remove_entry(self) -> (K, V) {
+        let res = match self.home {
+            Home::Bucket {
+                bucket,
+                hash,
+                value,
+            } => bucket
+                .remove_value(hash, value.as_ptr())
+                .expect("found entry is in its bucket"),
+            Home::Inline { inline, index } => inline.remove_entry_at(index),
+        };
+        *self.len -= 1;
+        res
+    }
+}
+
+/// A view into a single key's slot, returned by `HashMap::entry`
+pub enum Entry<'a, K, V, S = RandomState> {
+    Occupied(OccupiedEntry<'a, K, V, S>),
+    Vacant(VacantEntry<'a, K, V, S>),
+}
+
+impl<'a, K, V, S> Entry<'a, K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Like `or_insert_with`, but `default` gets to see the key before it
+    /// is moved into the map
+    pub fn or_insert_with_key<F: FnOnce(&K) -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let value = default(entry.key());
+                entry.insert(value)
+            }
+        }
+    }
+
+    /// Like `or_insert_with`, but `default` gets to look at the map to make
+    /// the value, for example to size it by the map's capacity. The map is
+    /// seen after any growth the insert needs and before the key goes in.
+    /// It is only borrowed shared, so `default` can read it but not change
+    /// it.
+    pub fn or_insert_with_map<F>(self, default: F) -> &'a mut V
+    where
+        F: FnOnce(&HashMap<K, V, S>) -> V,
+    {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(mut entry) => {
+                entry.make_room();
+                let value = default(entry.map);
+                entry.insert(value)
+            }
+        }
+    }
+
+    /// Like `or_insert_with`, for values that can fail to be made. If the
+    /// entry is vacant and `f` fails, nothing is inserted and the key is
+    /// dropped.
+    pub fn or_try_insert_with<E, F>(self, f: F) -> Result<&'a mut V, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+    {
+        match self {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(f()?)),
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    /// Calls `f` on the value, inserting `V::default()` first if there
+    /// isn't one. Like `and_modify(f).or_default()`, except new values go
+    /// through `f` too.
+    pub fn and_modify_or_default<F: FnOnce(&mut V)>(self, f: F) -> &'a mut V
+    where
+        V: Default,
+    {
+        let value = self.or_default();
+        f(value);
+        value
+    }
+
+    /// Calls `f` on the value if there is one, passing the entry on either
+    /// way
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Entry::Occupied(entry) = &mut self {
+            f(entry.get_mut());
+        }
+        self
+    }
+}
+
+/// An entry whose key is in the map. It remembers the bucket and hash, so
+/// none of its methods hash the key again.
+pub struct OccupiedEntry<'a, K, V, S = RandomState> {
+    map: &'a mut HashMap<K, V, S>,
+    /// The key's bucket, unused while the entries are kept inline
+    index: usize,
+    hash: u64,
+    key: K,
+}
+
+impl<'a, K, V, S> OccupiedEntry<'a, K, V, S>
+where
+    K: Eq,
+{
+    /// The key stored in the map, not the one passed to `entry`
+    pub fn key(&self) -> &K {
+        self.stored().0
+    }
+
+    pub fn get(&self) -> &V {
+        self.stored().1
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        Self::value_mut(self.map, self.index, self.hash, &self.key)
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        Self::value_mut(self.map, self.index, self.hash, &self.key)
+    }
+
+    /// The stored entry, looked up again in its bucket or inline
+    fn stored(&self) -> (&K, &V) {
+        let map = &*self.map;
+        let found = if map.table.is_empty() {
+            map.inline.get_key_value(&self.key, &mut Probes::default())
+        } else {
+            map.table[self.index].get_key_value(self.hash, &self.key, &mut Probes::default())
+        };
+        found.expect("occupied entry is in its bucket")
+    }
+
+    fn value_mut<'m>(map: &'m mut HashMap<K, V, S>, index: usize, hash: u64, key: &K) -> &'m mut V {
+        let found = if map.table.is_empty() {
+            map.inline.get_mut(key, &mut Probes::default())
+        } else {
+            map.table[index].get_mut(hash, key, &mut Probes::default())
+        };
+        found.expect("occupied entry is in its bucket")
+    }
+
+    /// Replaces the value, returning the old one. The stored key is kept.
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    /// Stores the key passed to `entry` in place of the equal one already in
+    /// the map, and returns the old one. Useful when equal keys can still
+    /// differ, or to get back the stored key's allocation.
+    pub fn replace_key(self) -> K {
+        let old = if self.map.table.is_empty() {
+            self.map.inline.replace_key(self.key)
+        } else {
+            self.map.table[self.index].replace_key(self.hash, self.key)
+        };
+        old.expect("occupied entry is in its bucket")
+    }
+
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    pub fn remove_entry(self) -> (K, V) {
+        self.map.note_change();
+        let res = if self.map.table.is_empty() {
+            self.map
+                .inline
+                .remove_entry(&self.key, &mut Probes::default())
+        } else {
+            self.map.table[self.index].remove_entry(self.hash, &self.key, &mut Probes::default())
+        };
+        let res = res.expect("occupied entry is in its bucket");
+        self.map.len -= 1;
+        res
+    }
+}
+
+/// An entry whose key isn't in the map yet. Inserting grows the table
+/// first if the key wouldn't fit, then places it in the grown table, so the
+/// returned reference is never moved by a resize.
+pub struct VacantEntry<'a, K, V, S = RandomState> {
+    map: &'a mut HashMap<K, V, S>,
+    /// The key's bucket, unused while the entries are kept inline
+    index: usize,
+    hash: u64,
+    key: K,
+}
+
+impl<'a, K, V, S> VacantEntry<'a, K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    pub fn insert(mut self, value: V) -> &'a mut V {
+        self.make_room();
+        self.map.note_change();
+        self.map.len += 1;
+        if self.map.table.is_empty() {
+            return self
+                .map
+                .inline
+                .get_or_insert_with(self.hash, self.key, || value)
+                .0;
+        }
+
+        self.map.prepare_insert(self.index);
+        self.map.table[self.index]
+            .get_or_insert_with(self.hash, self.key, || value)
+            .0
+    }
+
+    /// Allocates or grows the table if one more entry wouldn't fit inline
+    /// or under the load factor, and finds the key's bucket in the new
+    /// table
+    fn make_room(&mut self) {
+        if !self.map.fits_one_more() {
+            self.map.grow_for_one_more();
+            self.index = self.map.hash_index(self.hash);
+        }
+    }
+}
+
+impl<K, V, S> fmt::Debug for Entry<'_, K, V, S>
+where
+    K: Eq + fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Entry::Occupied(entry) => f.debug_tuple("Entry").field(entry).finish(),
+            Entry::Vacant(entry) => f.debug_tuple("Entry").field(entry).finish(),
+        }
+    }
+}
+
+impl<K, V, S> fmt::Debug for OccupiedEntry<'_, K, V, S>
+where
+    K: Eq + fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OccupiedEntry")
+            .field("key", self.key())
+            .field("value", self.get())
+            .finish()
+    }
+}
+
+impl<K, V, S> fmt::Debug for VacantEntry<'_, K, V, S>
+where
+    K: Eq + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("VacantEntry").field(&self.key).finish()
+    }
+}
+
+/// Writes `{k1 => v1, k2 => v2}` in unspecified order
+impl<K, V, S> fmt::Display for HashMap<K, V, S>
+where
+    K: fmt::Display,
+    V: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("{")?;
+        for (i, (k, v)) in self.iter().enumerate() {
+            let sep = if i == 0 { "" } else { ", " };
+            write!(f, "{}{} => {}", sep, k, v)?;
+        }
+        f.write_str("}")
+    }
+}
+
+/// Maps are equal when they hold the same entries, whatever their hashers
+impl<K, V, S1, S2> PartialEq<HashMap<K, V, S2>> for HashMap<K, V, S1>
+where
+    K: Hash + Eq,
+    V: PartialEq,
+    S1: BuildHasher,
+    S2: BuildHasher,
+{
+    fn eq(&self, other: &HashMap<K, V, S2>) -> bool {
+        if self.len() != other.len() {
+            return false;
+        }
+
+        self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<K, V, S> Eq for HashMap<K, V, S>
+where
+    K: Hash + Eq,
+    V: Eq,
+    S: BuildHasher,
+{
+}
+
+/// The bucket an `Iter` is currently walking
+enum BucketIter<'a, K, V> {
+    ListIter(linked_list::Iter<'a, K, V>),
+    TreeIter(avl_tree::Iter<'a, K, V>),
+    /// The map's inline entries, walked before any bucket
+    InlineIter(inline_map::Iter<'a, K, V>),
+    Empty,
+}
+
+// derive would needlessly require K: Clone and V: Clone
+impl<K, V> Clone for BucketIter<'_, K, V> {
+    fn clone(&self) -> Self {
+        match self {
+            BucketIter::ListIter(iter) => BucketIter::ListIter(iter.clone()),
+            BucketIter::TreeIter(iter) => BucketIter::TreeIter(iter.clone()),
+            BucketIter::InlineIter(iter) => BucketIter::InlineIter(iter.clone()),
+            BucketIter::Empty => BucketIter::Empty,
+        }
+    }
+}
+
+/// The map's generation when an iterator was made, checked on every step
+#[cfg(debug_assertions)]
+#[derive(Clone, Copy)]
+struct Generation<'a> {
+    current: &'a AtomicUsize,
+    start: usize,
+}
+
+#[cfg(debug_assertions)]
+impl<'a> Generation<'a> {
+    fn of(current: &'a AtomicUsize) -> Self {
+        let start = current.load(Ordering::Relaxed);
+        Generation { current, start }
+    }
+
+    fn check(&self) {
+        assert_eq!(
+            self.current.load(Ordering::Relaxed),
+            self.start,
+            "map mutated during iteration"
+        );
+    }
+}
+
+/// Iterator returned by `HashMap::iter`
+pub struct Iter<'a, K, V> {
+    table: &'a [Bucket<K, V>],
+    /// Next bucket to start on once `bucket` runs out
+    index: usize,
+    bucket: BucketIter<'a, K, V>,
+    remaining: usize,
+    #[cfg(debug_assertions)]
+    generation: Generation<'a>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        #[cfg(debug_assertions)]
+        self.generation.check();
+
+        loop {
+            let next = match &mut self.bucket {
+                BucketIter::ListIter(iter) => iter.next(),
+                BucketIter::TreeIter(iter) => iter.next(),
+                BucketIter::InlineIter(iter) => iter.next(),
+                BucketIter::Empty => None,
+            };
+            if next.is_some() {
+                self.remaining -= 1;
+                return next;
+            }
+
+            let entry = self.table.get(self.index)?;
+            self.index += 1;
+            self.bucket = match entry {
+                Bucket::ListEntry(list) => BucketIter::ListIter(list.iter()),
+                Bucket::TreeEntry(tree) => BucketIter::TreeIter(tree.iter()),
+                Bucket::Empty => BucketIter::Empty,
+            };
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V> ExactSizeIterator for Iter<'_, K, V> {}
+
+impl<K, V> Clone for Iter<'_, K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            table: self.table,
+            index: self.index,
+            bucket: self.bucket.clone(),
+            remaining: self.remaining,
+            #[cfg(debug_assertions)]
+            generation: self.generation,
+        }
+    }
+}
+
+/// Iterator returned by `HashMap::keys`
+pub struct Keys<'a, K, V> {
+    inner: Iter<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> ExactSizeIterator for Keys<'_, K, V> {}
+
+impl<K, V> Clone for Keys<'_, K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// Iterator returned by `HashMap::values`
+pub struct Values<'a, K, V> {
+    inner: Iter<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> ExactSizeIterator for Values<'_, K, V> {}
+
+impl<K, V> Clone for Values<'_, K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// The bucket an `IterMut` is currently walking
+enum BucketIterMut<'a, K, V> {
+    ListIter(linked_list::IterMut<'a, K, V>),
+    TreeIter(avl_tree::IterMut<'a, K, V>),
+    /// The map's inline entries, walked before any bucket
+    InlineIter(inline_map::IterMut<'a, K, V>),
+    Empty,
+}
+
+/// Iterator returned by `HashMap::iter_mut`
+pub struct IterMut<'a, K, V> {
+    table: std::slice::IterMut<'a, Bucket<K, V>>,
+    bucket: BucketIterMut<'a, K, V>,
+    remaining: usize,
+    #[cfg(debug_assertions)]
+    generation: Generation<'a>,
+}
+
+impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        #[cfg(debug_assertions)]
+        self.generation.check();
+
+        loop {
+            let next = match &mut self.bucket {
+                BucketIterMut::ListIter(iter) => iter.next(),
+                BucketIterMut::TreeIter(iter) => iter.next(),
+                BucketIterMut::InlineIter(iter) => iter.next(),
+                BucketIterMut::Empty => None,
+            };
+            if next.is_some() {
+                self.remaining -= 1;
+                return next;
+            }
+
+            self.bucket = match self.table.next()? {
+                Bucket::ListEntry(list) => BucketIterMut::ListIter(list.iter_mut()),
+                Bucket::TreeEntry(tree) => BucketIterMut::TreeIter(tree.iter_mut()),
+                Bucket::Empty => BucketIterMut::Empty,
+            };
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V> ExactSizeIterator for IterMut<'_, K, V> {}
+
+/// A range of buckets returned by `HashMap::split_at_buckets`
+pub struct BucketSliceMut<'a, K, V> {
+    /// The map's inline entries, in the first range only
+    inline: Option<&'a mut InlineMap<K, V>>,
+    buckets: &'a mut [Bucket<K, V>],
+    len: usize,
+    #[cfg(debug_assertions)]
+    generation: &'a AtomicUsize,
+}
+
+impl<'a, K, V> BucketSliceMut<'a, K, V> {
+    /// Number of entries in the range
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Borrows every entry in the range mutably, in the order
+    /// `HashMap::iter_mut` visits them
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut {
+            table: self.buckets.iter_mut(),
+            bucket: match &mut self.inline {
+                Some(inline) => BucketIterMut::InlineIter(inline.iter_mut()),
+                None => BucketIterMut::Empty,
+            },
+            remaining: self.len,
+            #[cfg(debug_assertions)]
+            generation: Generation::of(self.generation),
+        }
+    }
+}
+
+/// Iterator returned by `HashMap::values_mut`
+pub struct ValuesMut<'a, K, V> {
+    inner: IterMut<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for ValuesMut<'a, K, V> {
+    type Item = &'a mut V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> ExactSizeIterator for ValuesMut<'_, K, V> {}
+
+/// The bucket an `IntoIter` is currently draining
+enum BucketIntoIter<K, V> {
+    ListIter(linked_list::IntoIter<K, V>),
+    TreeIter(avl_tree::IntoIter<K, V>),
+    /// The map's inline entries, taken before any bucket
+    InlineIter(inline_map::IntoIter<K, V>),
+    Empty,
+}
+
+/// By value iterator over the entries of a `HashMap`
+pub struct IntoIter<K, V> {
+    table: std::vec::IntoIter<Bucket<K, V>>,
+    bucket: BucketIntoIter<K, V>,
+    remaining: usize,
+}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let next = match &mut self.bucket {
+                BucketIntoIter::ListIter(iter) => iter.next(),
+                BucketIntoIter::TreeIter(iter) => iter.next(),
+                BucketIntoIter::InlineIter(iter) => iter.next(),
+                BucketIntoIter::Empty => None,
+            };
+            if next.is_some() {
+                self.remaining -= 1;
+                return next;
+            }
+
+            self.bucket = match self.table.next()? {
+                Bucket::ListEntry(list) => BucketIntoIter::ListIter(list.into_iter()),
+                Bucket::TreeEntry(tree) => BucketIntoIter::TreeIter(tree.into_iter()),
+                Bucket::Empty => BucketIntoIter::Empty,
+            };
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoIter<K, V> {}
+
+impl<K, V, S> IntoIterator for HashMap<K, V, S> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter {
+            // by value iterator
+            table: Vec::from(self.table).into_iter(),
+            bucket: BucketIntoIter::InlineIter(self.inline.into_iter()),
+            remaining: self.len,
+        }
+    }
+}
+
+/// Iterator returned by `HashMap::drain`
+pub struct Drain<'a, K, V> {
+    table: std::slice::IterMut<'a, Bucket<K, V>>,
+    bucket: BucketIntoIter<K, V>,
+    remaining: usize,
+}
+
+impl<K, V> Iterator for Drain<'_, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let next = match &mut self.bucket {
+                BucketIntoIter::ListIter(iter) => iter.next(),
+                BucketIntoIter::TreeIter(iter) => iter.next(),
+                BucketIntoIter::InlineIter(iter) => iter.next(),
+                BucketIntoIter::Empty => None,
+            };
+            if next.is_some() {
+                self.remaining -= 1;
+                return next;
+            }
+
+            self.bucket = match mem::take(self.table.next()?) {
+                Bucket::ListEntry(list) => BucketIntoIter::ListIter(list.into_iter()),
+                Bucket::TreeEntry(tree) => BucketIntoIter::TreeIter(tree.into_iter()),
+                Bucket::Empty => BucketIntoIter::Empty,
+            };
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V> ExactSizeIterator for Drain<'_, K, V> {}
+
+// the map's len is already 0, so the buckets not reached yet have to be
+// emptied too
+impl<K, V> Drop for Drain<'_, K, V> {
+    fn drop(&mut self) {
+        for _ in self.by_ref() {}
+    }
+}
+
+/// Iterator returned by `HashMap::into_keys`
+pub struct IntoKeys<K, V> {
+    inner: IntoIter<K, V>,
+}
+
+impl<K, V> Iterator for IntoKeys<K, V> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoKeys<K, V> {}
+
+/// Iterator returned by `HashMap::into_values`
+pub struct IntoValues<K, V> {
+    inner: IntoIter<K, V>,
+}
+
+impl<K, V> Iterator for IntoValues<K, V> {
+    type Item = V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoValues<K, V> {}
+
+/// ```
+/// use maps::hashmap::HashMap;
+///
+/// let mut map = HashMap::new();
+/// map.insert("a", 1);
+/// map.insert("b", 2);
+///
+/// let mut sum = 0;
+/// for (_, v) in &map {
+///     sum += v;
+/// }
+/// assert_eq!(sum, 3);
+/// ```
+impl<'a, K, V, S> IntoIterator for &'a HashMap<K, V, S> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// ```
+/// use maps::hashmap::HashMap;
+///
+/// let mut map = HashMap::new();
+/// map.insert("a", 1);
+/// map.insert("b", 2);
+///
+/// for (_, v) in &mut map {
+///     *v *= 10;
+/// }
+/// assert_eq!(map.get("b"), Some(&20));
+/// ```
+impl<'a, K, V, S> IntoIterator for &'a mut HashMap<K, V, S> {
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+/// Iterator returned by `HashMap::into_iter_sorted`
+pub struct IntoIterSorted<K, V> {
+    entries: std::vec::IntoIter<(K, V)>,
+}
+
+impl<K, V> Iterator for IntoIterSorted<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoIterSorted<K, V> {}
+
+/// An empty map using the hasher's default, without allocating
+impl<K, V, S: Default> Default for HashMap<K, V, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K, V, S> Extend<(K, V)> for HashMap<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    /// Hashes and gathers the entries first, then inserts them as one
+    /// batch. Buckets the new keys crowd past `TREEIFY_THRESHOLD` are
+    /// turned into trees once, at the end, rather than partway through.
+    /// Otherwise it ends up just like inserting every entry in turn.
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let entries = iter
+            .into_iter()
+            .map(|(k, v)| (self.hash(&k), k, v))
+            .collect();
+        self.insert_batch(entries);
+    }
+}
+
+impl<K, V, S> FromIterator<(K, V)> for HashMap<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher + Default,
+{
+    /// Sizes the table from the iterator's lower size bound, which is the
+    /// exact length for `ExactSizeIterator` sources like `Vec` and ranges.
+    /// When a key appears more than once the last value wins, as with
+    /// repeated `insert`s.
+    ///
+    /// The entries are hashed and gathered first, then every bucket is
+    /// built in one go, so a bucket that many keys collide in becomes a
+    /// balanced tree directly instead of going through a list first. Each
+    /// bucket iterates in the same order repeated `insert`s would give.
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let iter = iter.into_iter();
+        let mut map = HashMap::with_capacity_and_hasher(iter.size_hint().0, S::default());
+
+        let entries = iter.map(|(k, v)| (map.hash(&k), k, v)).collect();
+        map.insert_batch(entries);
+
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::rc::Rc;
+
+    /// Counts how many values have been dropped
+    struct DropCounter(Rc<Cell<usize>>);
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Counts the allocations made on each thread, so a test can check what
+    /// a piece of code allocates
+    struct CountingAlloc;
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            // the thread's locals may already be gone while it exits
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    /// xorshift64, so randomized tests repeat exactly for a given seed
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    /// The number in environment variable `var`, or `default` if it's unset
+    fn env_or(var: &str, default: u64) -> u64 {
+        std::env::var(var).map_or(default, |v| v.parse().expect("not a number"))
+    }
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    /// Hashes integers to themselves so tests can pick bucket placement
+    #[derive(Clone, Default)]
+    struct IdentityState;
+
+    #[derive(Default)]
+    struct IdentityHasher(u64);
+
+    impl Hasher for IdentityHasher {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            for &b in bytes {
+                self.0 = self.0 << 8 | b as u64;
+            }
+        }
+
+        fn write_u64(&mut self, i: u64) {
+            self.0 = i;
+        }
+    }
+
+    impl BuildHasher for IdentityState {
+        type Hasher = IdentityHasher;
+
+        fn build_hasher(&self) -> IdentityHasher {
+            IdentityHasher::default()
+        }
+    }
+
+    /// Hashes every key to 0 so they all share one bucket
+    #[derive(Default)]
+    struct CollidingState;
+
+    struct CollidingHasher;
+
+    impl Hasher for CollidingHasher {
+        fn finish(&self) -> u64 {
+            0
+        }
+
+        fn write(&mut self, _bytes: &[u8]) {}
+    }
+
+    impl BuildHasher for CollidingState {
+        type Hasher = CollidingHasher;
+
+        fn build_hasher(&self) -> CollidingHasher {
+            CollidingHasher
+        }
+    }
+
+    /// Map with exactly `buckets` buckets, for tests that depend on the table
+    /// layout or on exactly when it grows
+    fn with_buckets<K, V, S>(buckets: usize, hash_builder: S) -> HashMap<K, V, S>
+    where
+        K: Hash + Eq,
+        S: BuildHasher,
+    {
+        let mut map = HashMap::with_hasher(hash_builder);
+        map.resize_to(buckets);
+        map
+    }
+
+    #[test]
+    fn empty_len() {
+        let map: HashMap<(), ()> = HashMap::new();
+        assert_eq!(map.len(), 0);
+    }
+
+    #[test]
+    fn get_non_existent_key() {
+        let map: HashMap<(), ()> = HashMap::new();
+        assert_eq!(map.get(&()), None);
+    }
+
+    #[test]
+    fn insert_one() {
+        let mut map = HashMap::new();
+        assert_eq!(map.insert(1, 2), None);
+        println!("{:?}", map.table);
+        assert_eq!(map.get(&1), Some(&2));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn insert_and_replace_one() {
+        let mut map = HashMap::new();
+        assert_eq!(map.insert(1, 2), None);
+        println!("{:?}", map.table);
+        assert_eq!(map.get(&1), Some(&2));
+        assert_eq!(map.insert(1, 3), Some(2));
+        println!("{:?}", map.table);
+        assert_eq!(map.get(&1), Some(&3));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn insert_many() {
+        let mut map = HashMap::new();
+        for i in 0..1000 {
+            assert_eq!(map.insert(i, i + 1), None);
+        }
+        for i in 0..1000 {
+            assert_eq!(map.get(&i), Some(&(i + 1)));
+        }
+    }
+
+    #[test]
+    fn insert_and_replace_many() {
+        let mut map = HashMap::new();
+        for i in 0..1000 {
+            assert_eq!(map.insert(i, i + 1), None);
+        }
+        for i in 0..1000 {
+            assert_eq!(map.get(&i), Some(&(i + 1)));
+        }
+
+        for i in 0..1000 {
+            assert_eq!(map.insert(i, i + i + 1), Some(i + 1));
+        }
+        for i in 0..1000 {
+            assert_eq!(map.get(&i), Some(&(i + i + 1)));
+        }
+    }
+
+    #[test]
+    fn insert_and_remove_one() {
+        let mut map = HashMap::new();
+        assert_eq!(map.insert(1, 2), None);
+        println!("{:?}", map.table);
+        assert_eq!(map.get(&1), Some(&2));
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.remove(&1), Some(2));
+        println!("{:?}", map.table);
+        assert_eq!(map.get(&1), None);
+        assert_eq!(map.len(), 0);
+    }
+
+    #[test]
+    fn insert_and_remove_many() {
+        let mut map = HashMap::new();
+        for i in 0..1000 {
+            assert_eq!(map.insert(i, i + 1), None);
+        }
+        for i in 0..1000 {
+            assert_eq!(map.get(&i), Some(&(i + 1)));
+        }
+
+        for i in 0..1000 {
+            assert_eq!(map.remove(&i), Some(i + 1));
+        }
+        for i in 0..1000 {
+            assert_eq!(map.get(&i), None);
+        }
+    }
+
+    #[test]
+    fn from_iter() {
+        let map: HashMap<_, _> = (0..1000).map(|i| (i, i + 1)).collect();
+
+        for i in 0..1000 {
+            assert_eq!(map.get(&i), Some(&(i + 1)));
+        }
+    }
+
+    #[test]
+    fn max_capacity_is_power_of_two() {
+        let cap = max_capacity::<u64, u64>();
+        assert!(cap.is_power_of_two());
+        assert!(
+            cap.checked_mul(mem::size_of::<Bucket<u64, u64>>()).unwrap() <= isize::MAX as usize
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "map at maximum capacity")]
+    fn grow_past_max_capacity() {
+        assert_eq!(grown_capacity(8, 16), 16);
+        grown_capacity(16, 16);
+    }
+
+    #[test]
+    fn bucket_index_of() {
+        let mut map = with_buckets(16, IdentityState);
+        map.insert(1u64, ());
+        map.insert(17u64, ());
+
+        assert_eq!(map.hash_of(&17), 17);
+        assert_eq!(map.bucket_index_of(&1), Some(1));
+        assert_eq!(map.bucket_index_of(&1), map.bucket_index_of(&17));
+        assert_ne!(map.bucket_index_of(&1), map.bucket_index_of(&2));
+    }
+
+    #[test]
+    fn with_seed() {
+        let mut a = HashMap::with_seed(1, 2);
+        let mut b = HashMap::with_seed(1, 2);
+        let mut c = HashMap::with_seed(2, 1);
+        for map in [&mut a, &mut b, &mut c].iter_mut() {
+            // past what is kept inline, so there are buckets to compare
+            map.reserve(8);
+            map.insert("key", ());
+        }
+
+        assert_eq!(a.hash_of("key"), b.hash_of("key"));
+        assert_eq!(a.bucket_index_of("key"), b.bucket_index_of("key"));
+        assert_ne!(a.bucket_index_of("key"), c.bucket_index_of("key"));
+    }
+
+    #[test]
+    fn seeded() {
+        let mut a = HashMap::seeded(7);
+        let mut b = HashMap::seeded(7);
+        let mut c = HashMap::seeded(8);
+        for map in [&mut a, &mut b, &mut c].iter_mut() {
+            for i in 0..100 {
+                map.insert(i, ());
+            }
+        }
+
+        let placement = |map: &HashMap<i32, (), FixedState>| {
+            (0..100)
+                .map(|i| map.bucket_index_of(&i).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(placement(&a), placement(&b));
+        assert_ne!(placement(&a), placement(&c));
+    }
+
+    #[test]
+    fn rehash_with() {
+        let mut map = HashMap::with_seed(1, 2);
+        for i in 0..100 {
+            map.insert(i, i * 2);
+        }
+
+        let rehashed = map.rehash_with(FixedState::with_seeds(3, 4));
+        assert_eq!(rehashed.len(), 100);
+        assert_eq!(rehashed.capacity(), 256);
+        assert_ne!(rehashed.hash_of(&0), map.hash_of(&0));
+        for i in 0..100 {
+            assert_eq!(rehashed.get(&i), Some(&(i * 2)));
+        }
+        assert_eq!(rehashed, map);
+    }
+
+    #[test]
+    fn with_hasher_is_lazy() {
+        let mut map = HashMap::with_hasher(IdentityState);
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.capacity(), 0);
+        assert_eq!(map.get(&1u64), None);
+        assert_eq!(map.get_mut(&1u64), None);
+        assert_eq!(map.remove(&1u64), None);
+        assert_eq!(map.bucket_index_of(&1u64), None);
+        assert_eq!(map.capacity(), 0);
+
+        map.insert(1u64, 2);
+        // still kept inline
+        assert_eq!(map.capacity(), 0);
+        assert_eq!(map.get(&1), Some(&2));
+    }
+
+    #[test]
+    fn lazy_table_allocates_only_for_writes() {
+        let mut map = HashMap::new();
+        assert_eq!(map.get("a"), None);
+        assert!(!map.contains_key("a"));
+        assert!(map.find_mut("a").is_none());
+        assert_eq!(map.capacity(), 0);
+
+        *map.get_or_insert_with("a", || 0) += 1;
+        assert_eq!(map.capacity(), 0);
+        assert_eq!(map.get("a"), Some(&1));
+        assert_eq!(map.len(), 1);
+
+        // only once there are more entries than fit inline
+        for key in ["b", "c", "d"] {
+            map.get_or_insert_with(key, || 0);
+        }
+        assert_eq!(map.capacity(), 0);
+        map.get_or_insert_with("e", || 0);
+        assert_eq!(map.capacity(), DEFAULT_CAPACITY);
+        assert_eq!(map.len(), 5);
+    }
+
+    #[test]
+    fn small_maps_do_not_allocate() {
+        let allocations = ALLOCATIONS.with(Cell::get);
+        let mut map = HashMap::with_hasher(IdentityState);
+        for i in 0..inline_map::CAPACITY as u64 {
+            assert_eq!(map.insert(i, i), None);
+        }
+        assert_eq!(map.insert(0, 10), Some(0));
+        *map.get_mut(&1).unwrap() += 10;
+        *map.entry(2).or_insert(0) += 10;
+        map.entry(3).and_modify(|v| *v += 10);
+        assert_eq!(*map.get_or_insert_with(3, || 0), 13);
+        assert_eq!(map.find_mut(&3).unwrap().remove(), 13);
+        assert_eq!(map.try_insert_no_grow(3, 13), Ok(None));
+        assert_eq!(map.remove(&2), Some(12));
+        map.insert_or_update(2, || 12, |_| {});
+        map.reserve(0);
+        map.shrink_to_fit();
+        assert_eq!(map.raw_entry().from_hash(1, |&k| k == 1), Some((&1, &11)));
+        assert!(map.contains_key(&0));
+        for (_, v) in map.iter_mut() {
+            *v += 1;
+        }
+        assert_eq!(map.values().sum::<u64>(), 50);
+        map.retain(|&k, _| k != 0);
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.drain().count(), 3);
+
+        assert_eq!(ALLOCATIONS.with(Cell::get) - allocations, 0);
+        assert_eq!(map.capacity(), 0);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn fifth_insert_moves_the_entries_into_a_table() {
+        use std::cell::RefCell;
+
+        let resizes = Rc::new(RefCell::new(Vec::new()));
+        let log = resizes.clone();
+        let mut map = HashMap::with_hasher(IdentityState);
+        map.set_resize_hook(Box::new(move |old, new| {
+            log.borrow_mut().push((old, new));
+        }));
+        for i in 0..inline_map::CAPACITY as u64 {
+            assert_eq!(map.checked_insert(i * 16, i), (None, false));
+        }
+        assert_eq!(map.capacity(), 0);
+        assert_eq!(map.bucket_index_of(&0), None);
+        // in the order they went in
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), [0, 16, 32, 48]);
+
+        assert_eq!(map.checked_insert(1, 4), (None, true));
+        assert_eq!(resizes.take(), vec![(0, DEFAULT_CAPACITY)]);
+        assert_eq!(map.len(), 5);
+        map.check_invariants();
+        // the four inline entries share bucket 0
+        let buckets: Vec<_> = map.iter_buckets().take(2).collect();
+        assert_eq!(
+            buckets,
+            [
+                (0, BucketKind::List { len: 4 }),
+                (1, BucketKind::List { len: 1 })
+            ]
+        );
+        for i in 0..4 {
+            assert_eq!(map.get(&(i * 16)), Some(&i));
+        }
+        assert_eq!(map.get(&1), Some(&4));
+
+        // and go back inline once the table is shrunk away
+        map.remove(&1);
+        map.shrink_to_fit();
+        assert_eq!(resizes.take(), vec![(DEFAULT_CAPACITY, 0)]);
+        assert_eq!(map.capacity(), 0);
+        map.check_invariants();
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), [0, 16, 32, 48]);
+    }
+
+    #[test]
+    fn inline_entries_through_the_rest_of_the_api() {
+        let mut map: HashMap<u64, u64, IdentityState> = (1..=3).map(|i| (i, i)).collect();
+        assert_eq!(map.capacity(), 0);
+
+        // a vacant entry on a full map allocates before the value goes in
+        map.insert(4, 4);
+        let seen = map.entry(5).or_insert_with_map(|map| map.capacity() as u64);
+        assert_eq!(*seen, DEFAULT_CAPACITY as u64);
+        map.remove(&5);
+        map.shrink_to_fit();
+        assert_eq!(map.capacity(), 0);
+
+        match map.entry(4) {
+            Entry::Occupied(entry) => {
+                assert_eq!(entry.key(), &4);
+                assert_eq!(entry.replace_key(), 4);
+            }
+            Entry::Vacant(_) => panic!("4 is inline"),
+        }
+        match map.entry(1) {
+            Entry::Occupied(entry) => assert_eq!(entry.remove_entry(), (1, 1)),
+            Entry::Vacant(_) => panic!("1 is inline"),
+        }
+
+        let clone = map.try_clone().unwrap();
+        assert_eq!(clone.capacity(), 0);
+        assert_eq!(clone.to_vec(), map.to_vec());
+        assert_eq!(map.raw_entry().from_hash(3, |&k| k == 3), Some((&3, &3)));
+        assert_eq!(map.raw_entry().from_hash(2, |&k| k == 3), None);
+
+        let mut odd = HashMap::with_hasher(IdentityState);
+        map.drain_filter_into(|&k, _| k % 2 == 1, &mut odd);
+        assert_eq!(odd.capacity(), 0);
+        assert_eq!(odd.keys().copied().collect::<Vec<_>>(), [3]);
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), [2, 4]);
+
+        // extend counts what is already inline before choosing to allocate
+        map.extend(vec![(2, 20), (6, 6)]);
+        assert_eq!(map.capacity(), 0);
+        map.extend(vec![(7, 7), (8, 8)]);
+        assert_eq!(map.capacity(), DEFAULT_CAPACITY);
+        map.check_invariants();
+
+        let mut entries: Vec<_> = map.into_iter().collect();
+        entries.sort_unstable();
+        assert_eq!(entries, [(2, 20), (4, 4), (6, 6), (7, 7), (8, 8)]);
+    }
+
+    #[test]
+    fn small_maps_match_std_across_the_inline_threshold() {
+        let mut rng = XorShift(0x5851_f42d_4c95_7f2d);
+        let mut map = HashMap::with_hasher(IdentityState);
+        let mut model = std::collections::HashMap::new();
+        for step in 0..4000u64 {
+            let r = rng.next_u64();
+            // few enough keys that the map keeps crossing the threshold
+            let key = (r >> 8) % 8;
+            match r % 8 {
+                0 | 1 => assert_eq!(map.insert(key, step), model.insert(key, step)),
+                2 => assert_eq!(map.remove(&key), model.remove(&key)),
+                3 => {
+                    *map.entry(key).or_insert(0) += 1;
+                    *model.entry(key).or_insert(0) += 1;
+                }
+                4 => assert_eq!(
+                    map.find_mut(&key).map(FoundEntry::remove),
+                    model.remove(&key)
+                ),
+                5 => {
+                    map.retain(|&k, _| k != key);
+                    model.retain(|&k, _| k != key);
+                }
+                6 => map.shrink_to_fit(),
+                _ => match map.try_insert_no_grow(key, step) {
+                    Ok(old) => assert_eq!(old, model.insert(key, step)),
+                    Err(_) => assert!(!model.contains_key(&key)),
+                },
+            }
+
+            map.check_invariants();
+            assert!(map.len() <= inline_map::CAPACITY || map.capacity() > 0);
+            let mut entries = map.to_vec();
+            entries.sort_unstable();
+            let mut expected: Vec<_> = model.iter().map(|(&k, &v)| (k, v)).collect();
+            expected.sort_unstable();
+            assert_eq!(entries, expected, "step {}", step);
+        }
+    }
+
+    #[test]
+    fn with_capacity_is_eager() {
+        let map: HashMap<u64, u64, _> = HashMap::with_capacity_and_hasher(5, IdentityState);
+        assert_eq!(map.capacity(), 8);
+    }
+
+    #[test]
+    fn resize_hook() {
+        use std::cell::RefCell;
+
+        let resizes = Rc::new(RefCell::new(Vec::new()));
+        let mut map = with_buckets(16, RandomState::new());
+        let log = resizes.clone();
+        map.set_resize_hook(Box::new(move |old, new| {
+            log.borrow_mut().push((old, new));
+        }));
+
+        for i in 0..24 {
+            map.insert(i, i);
+        }
+
+        assert_eq!(resizes.take(), vec![(16, 32), (32, 64)]);
+        for i in 0..24 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn iter() {
+        let mut map = with_buckets(64, IdentityState);
+        map.table[1] = Bucket::ListEntry(LinkedList::new());
+        for i in 0..10u64 {
+            map.insert(i * 64 + 1, i);
+            map.insert(i + 2, i);
+        }
+
+        assert_eq!(map.capacity(), 64);
+        let mut iter = map.iter();
+        assert_eq!(iter.len(), 20);
+        iter.next();
+        assert_eq!(iter.len(), 19);
+
+        let mut entries: Vec<_> = map.iter().map(|(&k, &v)| (k, v)).collect();
+        entries.sort();
+        let mut expected: Vec<_> = (0..10)
+            .map(|i| (i * 64 + 1, i))
+            .chain((0..10).map(|i| (i + 2, i)))
+            .collect();
+        expected.sort();
+        assert_eq!(entries, expected);
+
+        let empty: HashMap<u64, u64> = HashMap::new();
+        assert_eq!(empty.iter().next(), None);
+    }
+
+    #[test]
+    fn values_mut_does_not_allocate_per_step() {
+        let mut map = with_buckets(1024, IdentityState);
+        for i in 0..500u64 {
+            map.insert(i, i);
+        }
+        let allocations = ALLOCATIONS.with(Cell::get);
+        for v in map.values_mut() {
+            *v += 1;
+        }
+        assert_eq!(ALLOCATIONS.with(Cell::get), allocations);
+
+        // two crowded buckets that are trees
+        for i in 1..40u64 {
+            map.insert(i * 1024, i);
+            map.insert(i * 1024 + 1, i);
+        }
+        assert_eq!(map.stats().tree_buckets, 2);
+        let allocations = ALLOCATIONS.with(Cell::get);
+        for v in map.values_mut() {
+            *v += 1;
+        }
+        // only each tree's stack, sized to its height up front
+        assert_eq!(ALLOCATIONS.with(Cell::get), allocations + 2);
+        assert_eq!(map.get(&7), Some(&9));
+    }
+
+    #[test]
+    fn iter_order_within_a_bucket() {
+        // only the first field is hashed, so keys can share a hash
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        struct Key(u64, usize);
+
+        impl Hash for Key {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.0.hash(state);
+            }
+        }
+
+        fn keys_in_order(map: &HashMap<Key, (), IdentityState>) -> Vec<Key> {
+            map.keys().copied().collect()
+        }
+
+        // all in bucket 1 of 1024, and of 2048 after growing
+        let mut rng = XorShift(0x489);
+        let keys: Vec<Key> = (0..20)
+            .map(|n| Key(rng.next_u64() % 6 * 2048 + 1, n))
+            .collect();
+        let mut expected = Vec::new();
+        let mut map = with_buckets(1024, IdentityState);
+
+        for &key in &keys[..6] {
+            map.insert(key, ());
+            expected.push(key);
+        }
+        // stable, so equal hashes stay in insertion order
+        expected.sort_by_key(|key| key.0);
+        assert!(matches!(map.table[1], Bucket::ListEntry(_)));
+        assert_eq!(keys_in_order(&map), expected);
+
+        for &key in &keys[6..] {
+            map.get_or_insert_with(key, || ());
+            expected.push(key);
+        }
+        expected.sort_by_key(|key| key.0);
+        assert!(matches!(map.table[1], Bucket::TreeEntry(_)));
+        assert_eq!(keys_in_order(&map), expected);
+
+        map.resize_to(2048);
+        assert!(matches!(map.table[1], Bucket::TreeEntry(_)));
+        assert_eq!(keys_in_order(&map), expected);
+
+        for key in keys.iter().step_by(2).take(8) {
+            map.remove(key);
+            expected.retain(|k| k != key);
+        }
+        assert!(matches!(map.table[1], Bucket::TreeEntry(_)));
+        assert_eq!(keys_in_order(&map), expected);
+
+        while expected.len() > 4 {
+            map.remove(&expected.pop().unwrap());
+        }
+        assert!(matches!(map.table[1], Bucket::ListEntry(_)));
+        assert_eq!(keys_in_order(&map), expected);
+
+        // a bulk-built bucket matches the same inserts done one at a time
+        let collected: HashMap<Key, (), IdentityState> = keys.iter().map(|&k| (k, ())).collect();
+        let mut inserted = HashMap::with_capacity_and_hasher(keys.len(), IdentityState);
+        for &key in &keys {
+            inserted.insert(key, ());
+        }
+        assert_eq!(keys_in_order(&collected), keys_in_order(&inserted));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn iteration_does_not_trip_the_generation_check() {
+        let mut map = with_buckets(16, CollidingState);
+        for i in 0..20 {
+            map.insert(i, i);
+        }
+        map.insert(100, 100);
+        map.remove(&100);
+
+        let iter = map.iter();
+        assert_eq!(iter.clone().count(), 20);
+        assert_eq!(iter.count(), 20);
+        assert_eq!(map.keys().count(), 20);
+        assert_eq!(map.values().sum::<u64>(), 190);
+        for (_, v) in map.iter_mut() {
+            *v += 1;
+        }
+        for v in map.values_mut() {
+            *v += 1;
+        }
+        for i in 0..20 {
+            assert_eq!(map.get(&i), Some(&(i + 2)));
+        }
+
+        // iterators made after a change start from the new generation
+        map.insert(20, 20);
+        assert_eq!(map.iter().count(), 21);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "map mutated during iteration")]
+    fn generation_check_catches_changes() {
+        let mut map = HashMap::new();
+        map.insert(1, 1);
+        map.insert(2, 2);
+
+        let mut iter = map.iter();
+        iter.next();
+        // what an insert or removal behind the iterator's back would do
+        map.generation.fetch_add(1, Ordering::Relaxed);
+        iter.next();
+    }
+
+    #[test]
+    fn retain_closure_sees_each_entry() {
+        let mut map: HashMap<u64, u64> = (0..100).map(|i| (i, i * 10)).collect();
+        let mut seen = Vec::new();
+        map.retain(|&k, v| {
+            assert_eq!(*v, k * 10);
+            seen.push(k);
+            *v += 1;
+            k % 3 != 0
+        });
+
+        seen.sort_unstable();
+        assert_eq!(seen, (0..100).collect::<Vec<_>>());
+        assert_eq!(map.len(), 66);
+        for (&k, &v) in map.iter() {
+            assert!(k % 3 != 0);
+            assert_eq!(v, k * 10 + 1);
+        }
+    }
+
+    #[test]
+    fn every_change_is_noted() {
+        type Op = fn(&mut HashMap<u64, u64>);
+        let ops: [(&str, Op); 9] = [
+            ("drain", |map| {
+                map.drain().next();
+            }),
+            ("take_all", |map| {
+                map.take_all();
+            }),
+            ("retain_while", |map| {
+                map.retain_while(|&k, _| ControlFlow::Continue(k != 1))
+            }),
+            ("drain_filter_into", |map| {
+                map.drain_filter_into(|&k, _| k == 1, &mut HashMap::new())
+            }),
+            ("try_drain_filter_into", |map| {
+                map.try_drain_filter_into(|&k, _| k == 1, &mut HashMap::new())
+                    .unwrap()
+            }),
+            ("compact", |map| map.compact()),
+            ("FoundEntry::remove", |map| {
+                map.find_mut(&1).unwrap().remove();
+            }),
+            ("reserve_colliding", |map| map.reserve_colliding(0)),
+            ("clear", |map| map.clear()),
+        ];
+
+        for (name, op) in ops {
+            let mut map = HashMap::new().with_lookup_cache();
+            for i in 0..10 {
+                map.insert(i, i);
+            }
+            assert_eq!(map.get(&1), Some(&1));
+            assert!(map.cached_bucket().is_some());
+            #[cfg(debug_assertions)]
+            let before = *map.generation.get_mut();
+
+            op(&mut map);
+            assert!(map.cached_bucket().is_none(), "{} kept the cache", name);
+            #[cfg(debug_assertions)]
+            assert_ne!(
+                *map.generation.get_mut(),
+                before,
+                "{} kept the generation",
+                name
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    // aliases the map on purpose, as only unsafe code could
+    #[cfg_attr(miri, ignore)]
+    #[should_panic(expected = "map mutated during iteration")]
+    fn retain_catches_reentrant_insert() {
+        let mut map: HashMap<u64, u64> = (0..10).map(|i| (i, i)).collect();
+        let map_ptr: *mut HashMap<u64, u64> = &mut map;
+        map.retain(|&k, _| {
+            // an existing key, so nothing moves before the check
+            unsafe { (*map_ptr).insert(0, k) };
+            true
+        });
+    }
+
+    #[test]
+    fn iter_order_is_stable() {
+        // bucket 0 is a tree, the rest lists
+        let mut map = with_buckets(64, IdentityState);
+        for i in 0..30u64 {
+            map.insert(i * 64, i);
+        }
+        for i in 0..10u64 {
+            map.insert(i * 3 + 1, i);
+        }
+        assert_eq!(map.stats().tree_buckets, 1);
+
+        let first: Vec<_> = map.iter().collect();
+        assert_eq!(map.get(&64), Some(&1));
+        assert!(map.contains_key(&4));
+        map.stats();
+        assert_eq!(first, map.iter().collect::<Vec<_>>());
+        assert!(map.iter().zip(map.iter()).all(|(a, b)| a == b));
+    }
+
+    #[test]
+    fn keys_and_values() {
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            map.insert(i, i * 3);
+        }
+
+        let keys = map.keys();
+        assert_eq!(keys.len(), 100);
+        let mut keys: Vec<_> = keys.clone().copied().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, (0..100).collect::<Vec<_>>());
+
+        let mut values: Vec<_> = map.values().copied().collect();
+        values.sort_unstable();
+        assert_eq!(values, (0..100).map(|i| i * 3).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn iter_mut() {
+        let mut map = HashMap::new();
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+
+        let mut iter = map.iter_mut();
+        assert_eq!(iter.len(), 1000);
+        iter.next();
+        assert_eq!(iter.len(), 999);
+
+        for (_, v) in map.iter_mut() {
+            *v *= 2;
+        }
+        for i in 0..1000 {
+            assert_eq!(map.get(&i), Some(&(i * 2)));
+        }
+
+        let mut lists = with_buckets(16, IdentityState);
+        lists.table[1] = Bucket::ListEntry(LinkedList::new());
+        for &i in &[1u64, 17, 33, 2] {
+            lists.insert(i, i);
+        }
+        for (&k, v) in lists.iter_mut() {
+            *v += k;
+        }
+        for &i in &[1u64, 17, 33, 2] {
+            assert_eq!(lists.get(&i), Some(&(i * 2)));
+        }
+    }
+
+    #[test]
+    fn split_at_buckets() {
+        let mut map = HashMap::with_hasher(IdentityState);
+        for i in 0..1000u64 {
+            map.insert(i, i);
+        }
+        let cap = map.capacity() as u64;
+        // a tree bucket as well as lists
+        for i in 1..=20 {
+            map.insert(i * cap + 5, i * cap + 5);
+        }
+        assert_eq!(map.capacity() as u64, cap);
+        assert_eq!(map.stats().tree_buckets, 1);
+
+        let mut slices = map.split_at_buckets(4);
+        assert_eq!(slices.len(), 4);
+        assert_eq!(slices.iter().map(BucketSliceMut::len).sum::<usize>(), 1020);
+        for (range, slice) in slices.iter_mut().enumerate() {
+            let range = range as u64;
+            assert_eq!(slice.iter_mut().len(), slice.len());
+            for (&k, v) in slice.iter_mut() {
+                // each range holds the keys of its own quarter of the table
+                assert_eq!(k % cap * 4 / cap, range);
+                *v += 1;
+            }
+        }
+        drop(slices);
+        assert_eq!(map.len(), 1020);
+        for (&k, &v) in &map {
+            assert_eq!(v, k + 1);
+        }
+
+        let mut empty: HashMap<u64, u64> = HashMap::new();
+        let slices = empty.split_at_buckets(3);
+        assert_eq!(slices.len(), 3);
+        assert!(slices.iter().all(BucketSliceMut::is_empty));
+
+        // inline entries all go in the first range
+        let mut small: HashMap<u64, u64> = (0..3).map(|i| (i, i)).collect();
+        let mut slices = small.split_at_buckets(3);
+        assert_eq!(slices[0].len(), 3);
+        assert!(slices[1..].iter().all(BucketSliceMut::is_empty));
+        for (_, v) in slices[0].iter_mut() {
+            *v += 1;
+        }
+        drop(slices);
+        assert_eq!(small.get(&2), Some(&3));
+    }
+
+    #[test]
+    fn values_mut() {
+        let mut map = HashMap::new();
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+
+        let values = map.values_mut();
+        assert_eq!(values.len(), 1000);
+        for v in values {
+            *v = (*v).min(500);
+        }
+        for i in 0..1000 {
+            assert_eq!(map.get(&i), Some(&i.min(500)));
+        }
+    }
+
+    #[test]
+    fn into_iter() {
+        let drops = Rc::new(Cell::new(0));
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            map.insert(i, DropCounter(drops.clone()));
+        }
+
+        let mut iter = map.into_iter();
+        assert_eq!(iter.len(), 100);
+        let mut keys: Vec<_> = iter.by_ref().take(60).map(|(k, _)| k).collect();
+        assert_eq!(iter.len(), 40);
+        keys.extend(iter.map(|(k, _)| k));
+        keys.sort_unstable();
+        assert_eq!(keys, (0..100).collect::<Vec<_>>());
+        assert_eq!(drops.get(), 100);
+    }
+
+    #[test]
+    fn iterate_by_reference() {
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+
+        for (_, v) in &mut map {
+            *v += 1;
+        }
+        let mut count = 0;
+        for (&k, &v) in &map {
+            assert_eq!(v, k + 1);
+            count += 1;
+        }
+        assert_eq!(count, 100);
+
+        // never allocated, so the table has no buckets at all
+        let mut empty: HashMap<i32, i32> = HashMap::new();
+        assert_eq!((&empty).into_iter().count(), 0);
+        assert_eq!((&mut empty).into_iter().count(), 0);
+        assert_eq!(empty.into_iter().count(), 0);
+    }
+
+    #[test]
+    fn into_keys_and_values() {
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            map.insert(i, i % 10);
+        }
+
+        let keys = map.try_clone().unwrap().into_keys();
+        assert_eq!(keys.len(), 100);
+        let mut keys: Vec<_> = keys.collect();
+        keys.sort_unstable();
+        assert_eq!(keys, (0..100).collect::<Vec<_>>());
+
+        let values = map.into_values();
+        assert_eq!(values.len(), 100);
+        let mut values: Vec<_> = values.collect();
+        values.sort_unstable();
+        let expected: Vec<_> = (0..10).flat_map(|v| vec![v; 10]).collect();
+        assert_eq!(values, expected);
+    }
+
+    #[test]
+    fn clear() {
+        let drops = Rc::new(Cell::new(0));
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            map.insert(i, DropCounter(drops.clone()));
+        }
+        let capacity = map.capacity();
+
+        map.clear();
+        assert_eq!(drops.get(), 100);
+        assert!(map.is_empty());
+        assert_eq!(map.capacity(), capacity);
+        assert!(map.get(&1).is_none());
+        map.check_invariants();
+
+        map.insert(1, DropCounter(drops.clone()));
+        assert!(map.contains_key(&1));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn drain() {
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+        let capacity = map.capacity();
+
+        let drain = map.drain();
+        assert_eq!(drain.len(), 100);
+        let mut entries: Vec<_> = drain.collect();
+        entries.sort_unstable();
+        assert_eq!(entries, (0..100).map(|i| (i, i)).collect::<Vec<_>>());
+        assert!(map.is_empty());
+        assert_eq!(map.capacity(), capacity);
+        map.check_invariants();
+    }
+
+    #[test]
+    fn drain_dropped_early() {
+        let drops = Rc::new(Cell::new(0));
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            map.insert(i, DropCounter(drops.clone()));
+        }
+        let capacity = map.capacity();
+
+        let mut drain = map.drain();
+        for _ in 0..10 {
+            drain.next();
+        }
+        drop(drain);
+
+        assert_eq!(drops.get(), 100);
+        assert!(map.is_empty());
+        assert_eq!(map.capacity(), capacity);
+        assert!(map.get(&5).is_none());
+        map.check_invariants();
+
+        map.insert(5, DropCounter(drops.clone()));
+        assert_eq!(map.len(), 1);
+        assert!(map.contains_key(&5));
+        assert_eq!(map.capacity(), capacity);
+    }
+
+    #[test]
+    fn to_vec() {
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            map.insert(i, i + 1);
+        }
+
+        let mut entries = map.to_vec();
+        assert_eq!(entries.len(), map.len());
+        entries.sort();
+        assert_eq!(entries, (0..100).map(|i| (i, i + 1)).collect::<Vec<_>>());
+        assert_eq!(map.len(), 100);
+    }
+
+    #[test]
+    fn take_all() {
+        let mut map: HashMap<_, _> = (0..100).map(|i| (i, i + 1)).collect();
+        let capacity = map.capacity();
+
+        let mut entries = map.take_all();
+        assert_eq!(entries.len(), 100);
+        entries.sort();
+        assert_eq!(entries, (0..100).map(|i| (i, i + 1)).collect::<Vec<_>>());
+
+        assert!(map.is_empty());
+        assert_eq!(map.capacity(), capacity);
+        assert_eq!(map.get(&0), None);
+        map.insert(0, 0);
+        assert_eq!(map.get(&0), Some(&0));
+    }
+
+    #[test]
+    fn merge_with() {
+        let mut a: HashMap<_, _> = vec![("x", 1), ("y", 2)].into_iter().collect();
+        let b: HashMap<_, _> = vec![("y", 10), ("z", 20)].into_iter().collect();
+
+        a.merge_with(b, |_, existing, incoming| *existing += incoming);
+
+        assert_eq!(a.len(), 3);
+        assert_eq!(a.get("x"), Some(&1));
+        assert_eq!(a.get("y"), Some(&12));
+        assert_eq!(a.get("z"), Some(&20));
+    }
+
+    #[test]
+    fn get_or_insert_with() {
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            *map.get_or_insert_with(i % 10, || 0) += 1;
+        }
+
+        assert_eq!(map.len(), 10);
+        for i in 0..10 {
+            assert_eq!(map.get(&i), Some(&10));
+        }
+
+        // enough new keys to grow the table while holding the reference
+        for i in 10..1000 {
+            let value = map.get_or_insert_with(i, || 0);
+            *value = i;
+        }
+        for i in 10..1000 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn checked_insert() {
+        let mut map = with_buckets(16, RandomState::new());
+        for i in 0..11 {
+            assert_eq!(map.checked_insert(i, i), (None, false));
+        }
+        assert_eq!(map.checked_insert(11, 11), (None, true));
+        assert_eq!(map.capacity(), 32);
+        assert_eq!(map.checked_insert(11, 12), (Some(11), false));
+
+        let mut lazy = HashMap::new();
+        for i in 0..inline_map::CAPACITY {
+            assert_eq!(lazy.checked_insert(i, i), (None, false));
+        }
+        assert_eq!(lazy.checked_insert(0, 1), (Some(0), false));
+        assert_eq!(lazy.checked_insert(99, 99), (None, true));
+    }
+
+    #[test]
+    fn stats() {
+        let mut map = with_buckets(16, IdentityState);
+        for &i in &[1u64, 17, 33, 2] {
+            map.insert(i, ());
+        }
+
+        assert_eq!(
+            map.stats(),
+            MapStats {
+                len: 4,
+                capacity: 16,
+                load: 0.25,
+                empty_buckets: 14,
+                list_buckets: 2,
+                tree_buckets: 0,
+                longest_chain: 3,
+                max_tree_height: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn remove_resets_emptied_bucket() {
+        let mut map = with_buckets(16, IdentityState);
+        let mut list = LinkedList::new();
+        list.insert(1, 1u64, 1);
+        list.insert(17, 17u64, 17);
+        map.table[1] = Bucket::ListEntry(list);
+        map.len = 2;
+
+        assert_eq!(map.remove(&1), Some(1));
+        assert!(matches!(map.table[1], Bucket::ListEntry(_)));
+        assert_eq!(map.remove(&33), None);
+        assert!(matches!(map.table[1], Bucket::ListEntry(_)));
+        assert_eq!(map.remove(&17), Some(17));
+        assert!(matches!(map.table[1], Bucket::Empty));
+        assert_eq!(map.len(), 0);
+
+        // after reserve_colliding even a single entry goes in a tree
+        map.reserve_colliding(0);
+        map.insert(2, 2);
+        assert!(matches!(map.table[2], Bucket::TreeEntry(_)));
+        assert_eq!(map.remove(&18), None);
+        assert!(matches!(map.table[2], Bucket::TreeEntry(_)));
+        assert_eq!(map.remove(&2), Some(2));
+        assert!(matches!(map.table[2], Bucket::Empty));
+        assert_eq!(map.len(), 0);
+    }
+
+    #[test]
+    fn collect_with_custom_hasher() {
+        let map: HashMap<u64, u64, IdentityState> = (0..100).map(|i| (i, i * 2)).collect();
+        assert_eq!(map.len(), 100);
+        assert_eq!(map.capacity(), 256);
+        for i in 0..100 {
+            assert_eq!(map.get(&i), Some(&(i * 2)));
+            // IdentityState hashes keys to themselves
+            assert_eq!(map.bucket_index_of(&i), Some(i as usize));
+        }
+
+        let empty: HashMap<u64, u64, IdentityState> = HashMap::default();
+        assert!(empty.is_empty());
+        assert_eq!(empty.capacity(), 0);
+    }
+
+    #[test]
+    fn eq_across_hashers() {
+        let mut fixed = HashMap::with_seed(1, 2);
+        let mut random = HashMap::new();
+        for i in 0..100 {
+            fixed.insert(i, i + 1);
+            random.insert(99 - i, 100 - i);
+        }
+        assert!(fixed == random);
+        assert!(random == fixed);
+
+        random.insert(0, 0);
+        assert!(fixed != random);
+        random.insert(100, 101);
+        random.insert(0, 1);
+        assert!(fixed != random);
+    }
+
+    #[test]
+    fn eq_ignores_bucket_layout() {
+        let mut trees = with_buckets(64, CollidingState);
+        let mut lists = with_buckets(64, IdentityState);
+        for i in 0..20u64 {
+            trees.insert(i, i.to_string());
+            lists.insert(19 - i, (19 - i).to_string());
+        }
+        assert_eq!(trees.stats().tree_buckets, 1);
+        assert_eq!(lists.stats().tree_buckets, 0);
+        assert!(trees == lists);
+
+        fn assert_eq_impl<T: Eq>(_: &T) {}
+        let mut other = with_buckets(64, CollidingState);
+        for i in (0..20u64).rev() {
+            other.insert(i, i.to_string());
+        }
+        assert_eq_impl(&other);
+        assert!(trees == other);
+
+        other.insert(3, "three".to_string());
+        assert!(trees != other);
+    }
+
+    #[test]
+    fn find_mut() {
+        use std::collections::hash_map::DefaultHasher;
+
+        struct CountingState(Rc<Cell<usize>>);
+
+        impl BuildHasher for CountingState {
+            type Hasher = DefaultHasher;
+
+            fn build_hasher(&self) -> DefaultHasher {
+                self.0.set(self.0.get() + 1);
+                DefaultHasher::new()
+            }
+        }
+
+        let hashes = Rc::new(Cell::new(0));
+        let mut map = with_buckets(16, CountingState(hashes.clone()));
+        map.insert(3, 4);
+        for i in 4..10 {
+            map.insert(i, i + 1);
+        }
+        hashes.set(0);
+
+        let mut found = map.find_mut(&3).unwrap();
+        assert_eq!(*found.get(), 4);
+        *found.get_mut() += 1;
+        assert_eq!(found.remove_entry(), (3, 5));
+        assert_eq!(hashes.get(), 1);
+
+        assert_eq!(map.len(), 6);
+        assert_eq!(map.get(&3), None);
+        assert!(map.find_mut(&3).is_none());
+        assert_eq!(*map.find_mut(&4).unwrap().into_mut(), 5);
+    }
+
+    #[test]
+    fn found_entry_compares_no_more_keys() {
+        /// Counts every comparison made against it
+        struct Key(u64, Rc<Cell<usize>>);
+
+        impl PartialEq for Key {
+            fn eq(&self, other: &Key) -> bool {
+                self.1.set(self.1.get() + 1);
+                self.0 == other.0
+            }
+        }
+
+        impl Eq for Key {}
+
+        impl Hash for Key {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                state.write_u64(self.0);
+            }
+        }
+
+        let compares = Rc::new(Cell::new(0));
+        for trees in [false, true] {
+            // 3, 19, 35 and 51 share bucket 3
+            let mut map = with_buckets(16, IdentityState);
+            if trees {
+                map.reserve_colliding(0);
+            }
+            for i in 0..4 {
+                map.insert(Key(3 + 16 * i, compares.clone()), i);
+            }
+            assert_eq!(map.stats().tree_buckets, trees as usize);
+
+            compares.set(0);
+            let target = Key(35, compares.clone());
+            let mut found = map.find_mut(&target).unwrap();
+            let lookup = compares.get();
+            assert!(lookup > 0);
+            assert_eq!(*found.get(), 2);
+            *found.get_mut() += 10;
+            assert_eq!(found.remove(), 12);
+            assert_eq!(compares.get(), lookup, "remove searched again");
+
+            assert_eq!(map.len(), 3);
+            assert_eq!(map.get(&target), None);
+            for i in [0, 1, 3] {
+                assert_eq!(map.get(&Key(3 + 16 * i, compares.clone())), Some(&i));
+            }
+        }
+    }
+
+    #[test]
+    fn display() {
+        let mut map = HashMap::new();
+        assert_eq!(map.to_string(), "{}");
+
+        map.insert("a".to_string(), 1);
+        assert_eq!(map.to_string(), "{a => 1}");
+
+        map.insert("b".to_string(), 2);
+        let s = map.to_string();
+        assert!(s == "{a => 1, b => 2}" || s == "{b => 2, a => 1}", "{}", s);
+    }
+
+    #[test]
+    fn try_insert_no_grow() {
+        let mut map = with_buckets(16, RandomState::new());
+        for i in 0..11 {
+            assert_eq!(map.try_insert_no_grow(i, i), Ok(None));
+        }
+        assert_eq!(map.try_insert_no_grow(11, 11), Err((11, 11)));
+        assert_eq!(map.try_insert_no_grow(0, 1), Ok(Some(0)));
+        assert_eq!(map.get(&0), Some(&1));
+        assert_eq!(map.get(&11), None);
+        assert_eq!(map.len(), 11);
+        assert_eq!(map.capacity(), 16);
+
+        let mut lazy = HashMap::new();
+        for i in 0..inline_map::CAPACITY {
+            assert_eq!(lazy.try_insert_no_grow(i, i), Ok(None));
+        }
+        assert_eq!(lazy.try_insert_no_grow(99, 99), Err((99, 99)));
+        assert_eq!(lazy.capacity(), 0);
+    }
+
+    #[test]
+    fn from_iter_last_value_wins() {
+        let map: HashMap<_, _> = vec![(1, "a"), (1, "b")].into_iter().collect();
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get(&1), Some(&"b"));
+
+        // same through a list bucket
+        let mut map = with_buckets(16, IdentityState);
+        map.table[1] = Bucket::ListEntry(LinkedList::new());
+        map.insert(1u64, "a");
+        map.insert(1u64, "b");
+        assert_eq!(map.get(&1), Some(&"b"));
+    }
+
+    #[test]
+    fn from_iter_colliding_keys() {
+        use crate::avl_tree::ROTATIONS;
+
+        // every key in one bucket, with a few repeated
+        let keys: Vec<u64> = (0..200).chain(0..20).collect();
+        let before = ROTATIONS.with(Cell::get);
+        let map: HashMap<u64, usize, CollidingState> =
+            keys.iter().enumerate().map(|(n, &k)| (k, n)).collect();
+        // built as a tree outright, not inserted into one
+        assert_eq!(ROTATIONS.with(Cell::get), before);
+
+        assert_eq!(map.len(), 200);
+        map.check_invariants();
+        match &map.table[0] {
+            Bucket::TreeEntry(tree) => tree.check_balanced(),
+            _ => panic!("the shared bucket should be a tree"),
+        }
+        for k in 0..200 {
+            let last = if k < 20 { 200 + k } else { k };
+            assert_eq!(map.get(&(k as u64)), Some(&last));
+        }
+
+        // distinct hashes sharing buckets, one past the threshold and one
+        // right at it, with the first few keys repeated
+        let keys: Vec<u64> = (0..16)
+            .map(|i| i * 128)
+            .chain((0..8).map(|i| 1 + i * 128))
+            .chain(2..50)
+            .chain((0..4).map(|i| i * 128))
+            .collect();
+        let map: HashMap<u64, usize, IdentityState> =
+            keys.iter().enumerate().map(|(n, &k)| (k, n)).collect();
+        assert_eq!(map.len(), 72);
+        assert_eq!(map.capacity(), 128);
+        map.check_invariants();
+        let kinds: Vec<_> = map.iter_buckets().map(|(_, kind)| kind).collect();
+        assert!(matches!(kinds[0], BucketKind::Tree { len: 16, .. }));
+        assert_eq!(kinds[1], BucketKind::List { len: 8 });
+        for (n, k) in keys.iter().enumerate() {
+            let last = keys.iter().rposition(|other| other == k).unwrap();
+            if n == last {
+                assert_eq!(map.get(k), Some(&n));
+            }
+        }
+    }
+
+    #[test]
+    fn extend_colliding_keys() {
+        use crate::avl_tree::ROTATIONS;
+
+        // a list bucket the batch pushes well past the threshold
+        let mut map = with_buckets(1024, CollidingState);
+        for i in 0..5u64 {
+            map.insert(i, i);
+        }
+        let before = ROTATIONS.with(Cell::get);
+        map.extend((3..30).chain(3..6).map(|i| (i, i + 100)));
+        // turned into a tree once, not inserted into one
+        assert_eq!(ROTATIONS.with(Cell::get), before);
+        assert_eq!(map.len(), 30);
+        map.check_invariants();
+        match &map.table[0] {
+            Bucket::TreeEntry(tree) => tree.check_balanced(),
+            _ => panic!("the shared bucket should be a tree"),
+        }
+        for i in 0..30 {
+            let expected = if i < 3 { i } else { i + 100 };
+            assert_eq!(map.get(&i), Some(&expected));
+        }
+
+        // and a tree bucket that keeps growing
+        map.extend((30..100).map(|i| (i, i)));
+        assert_eq!(map.len(), 100);
+        map.check_invariants();
+        match &map.table[0] {
+            Bucket::TreeEntry(tree) => tree.check_balanced(),
+            _ => panic!("the shared bucket should still be a tree"),
+        }
+
+        // ends up like inserting in turn, resizes and all
+        let keys: Vec<u64> = (0..200).map(|i| i % 16 * 1024 + i / 16).collect();
+        let mut extended = with_buckets(16, IdentityState);
+        let mut inserted = with_buckets(16, IdentityState);
+        for i in 0..10 {
+            extended.insert(i * 16, i);
+            inserted.insert(i * 16, i);
+        }
+        extended.extend(keys.iter().map(|&k| (k, k)));
+        for &k in &keys {
+            inserted.insert(k, k);
+        }
+        extended.check_invariants();
+        assert_eq!(extended.capacity(), inserted.capacity());
+        assert!(extended == inserted);
+        assert!(inserted.stats().tree_buckets > 0);
+        assert_eq!(extended.stats().tree_buckets, inserted.stats().tree_buckets);
+    }
+
+    #[test]
+    fn from_iter_presizes_exact_size() {
+        let pairs: Vec<_> = (0..10_000).map(|i| (i, i)).collect();
+        let map: HashMap<_, _> = pairs.into_iter().collect();
+        assert_eq!(map.capacity(), capacity_for(10_000));
+        assert_eq!(map.capacity(), 16384);
+
+        // the table is sized from the hint up front rather than grown
+        let map: HashMap<_, _> = std::iter::repeat_n((0, 0), 10_000).collect();
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.capacity(), 16384);
+    }
+
+    #[test]
+    fn clear_ordered() {
+        use std::cell::RefCell;
+
+        struct Logged(u64, Rc<RefCell<Vec<u64>>>);
+
+        impl Drop for Logged {
+            fn drop(&mut self) {
+                self.1.borrow_mut().push(self.0);
+            }
+        }
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut map = HashMap::new();
+        for &i in &[5, 3, 9, 1, 7, 0, 8, 2, 6, 4] {
+            map.insert(i, Logged(i, log.clone()));
+        }
+
+        map.clear_ordered();
+        assert!(map.is_empty());
+        assert_eq!(log.take(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn lookups_through_list_and_tree_buckets() {
+        let mut map = with_buckets(16, IdentityState);
+        map.table[2] = Bucket::TreeEntry(AvlTree::new());
+        for &i in &[1u64, 17, 33, 2, 18] {
+            assert_eq!(map.insert(i, i), None);
+        }
+        assert!(matches!(map.table[1], Bucket::ListEntry(_)));
+        assert!(matches!(map.table[2], Bucket::TreeEntry(_)));
+
+        for &i in &[1u64, 17, 33, 2, 18] {
+            assert_eq!(map.get(&i), Some(&i));
+            assert_eq!(map.get_key_value(&i), Some((&i, &i)));
+            *map.get_mut(&i).unwrap() += 1;
+            assert_eq!(map.insert(i, i + 2), Some(i + 1));
+        }
+        for &i in &[49u64, 34, 3] {
+            assert_eq!(map.get(&i), None);
+            assert_eq!(map.get_key_value(&i), None);
+            assert_eq!(map.get_mut(&i), None);
+        }
+        assert_eq!(map.remove(&17), Some(19));
+        assert_eq!(map.get(&17), None);
+        assert_eq!(map.len(), 4);
+    }
+
+    #[test]
+    fn new_buckets_are_lists() {
+        let mut map = with_buckets(16, IdentityState);
+        for &i in &[1u64, 17, 2] {
+            assert_eq!(map.insert(i, i), None);
+        }
+        *map.get_or_insert_with(3, || 0) += 3;
+        assert!(matches!(map.table[1], Bucket::ListEntry(_)));
+        assert!(matches!(map.table[2], Bucket::ListEntry(_)));
+        assert!(matches!(map.table[3], Bucket::ListEntry(_)));
+
+        for &i in &[1u64, 17, 2, 3] {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+        assert_eq!(map.remove(&17), Some(17));
+        assert_eq!(map.remove(&2), Some(2));
+        assert!(matches!(map.table[1], Bucket::ListEntry(_)));
+        assert!(matches!(map.table[2], Bucket::Empty));
+        assert_eq!(map.get(&1), Some(&1));
+        assert_eq!(map.get(&17), None);
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn full_list_bucket_becomes_a_tree() {
+        let threshold = Bucket::<u64, u64>::TREEIFY_THRESHOLD as u64;
+        let mut map = with_buckets(1024, CollidingState);
+        for i in 0..threshold {
+            map.insert(i, i);
+        }
+        assert_eq!(map.stats().list_buckets, 1);
+        // updating a key in a full list still treeifies it
+        assert_eq!(map.insert(0, 0), Some(0));
+        assert_eq!(map.stats().tree_buckets, 1);
+
+        for i in threshold..100 {
+            *map.get_or_insert_with(i, || 0) += i;
+        }
+        assert_eq!(map.stats().tree_buckets, 1);
+        assert_eq!(map.stats().list_buckets, 0);
+        for i in 0..100 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+
+        let mut map = with_buckets(1024, CollidingState);
+        for i in 0..=threshold {
+            *map.get_or_insert_with(i, || 0) += i;
+        }
+        assert_eq!(map.stats().tree_buckets, 1);
+    }
+
+    #[test]
+    fn shrunk_tree_bucket_becomes_a_list() {
+        let threshold = Bucket::<u64, u64>::UNTREEIFY_THRESHOLD as u64;
+        let mut map = with_buckets(1024, CollidingState);
+        for i in 0..20 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.stats().tree_buckets, 1);
+
+        let kept = threshold - 1;
+        for i in kept..20 {
+            assert_eq!(map.stats().tree_buckets, 1);
+            assert_eq!(map.remove(&i), Some(i));
+        }
+        assert_eq!(map.stats().tree_buckets, 0);
+        assert_eq!(map.stats().list_buckets, 1);
+        assert_eq!(map.len(), kept as usize);
+        for i in 0..20 {
+            let expected = if i < kept { Some(&i) } else { None };
+            assert_eq!(map.get(&i), expected);
+        }
+
+        // misses leave the tree alone
+        let mut map = with_buckets(1024, CollidingState);
+        map.table[0] = Bucket::TreeEntry(AvlTree::new());
+        map.insert(0, 0);
+        assert_eq!(map.remove(&1), None);
+        assert_eq!(map.stats().tree_buckets, 1);
+    }
+
+    #[test]
+    fn tree_bucket_untreeifies_at_the_crossing() {
+        fn kind<S>(map: &HashMap<u64, u64, S>) -> BucketKind {
+            map.iter_buckets().next().unwrap().1
+        }
+        let treeify = Bucket::<u64, u64>::TREEIFY_THRESHOLD as u64;
+        let untreeify = Bucket::<u64, u64>::UNTREEIFY_THRESHOLD as u64;
+
+        let mut map = with_buckets(1024, CollidingState);
+        for i in 0..12 {
+            map.insert(i, i);
+        }
+
+        // removing one key at a time, through both removal paths
+        for i in (0..12).rev() {
+            if i % 2 == 0 {
+                assert_eq!(map.remove(&i), Some(i));
+            } else {
+                match map.entry(i) {
+                    Entry::Occupied(entry) => assert_eq!(entry.remove(), i),
+                    Entry::Vacant(_) => panic!("{} should be in the map", i),
+                }
+            }
+
+            let len = i as usize;
+            if i >= untreeify {
+                assert!(matches!(kind(&map), BucketKind::Tree { len: l, .. } if l == len));
+            } else if i > 0 {
+                assert_eq!(kind(&map), BucketKind::List { len });
+            }
+            map.check_invariants();
+        }
+        assert_eq!(kind(&map), BucketKind::Empty);
+
+        // between the two thresholds a bucket keeps whichever kind it is
+        for i in 0..untreeify - 1 {
+            map.insert(i, i);
+        }
+        for i in untreeify - 1..treeify {
+            map.insert(i, i);
+            assert!(matches!(kind(&map), BucketKind::List { .. }));
+        }
+        map.insert(treeify, treeify);
+        for i in (untreeify..=treeify).rev() {
+            map.remove(&i);
+            assert!(matches!(kind(&map), BucketKind::Tree { .. }));
+        }
+        map.remove(&(untreeify - 1));
+        assert!(matches!(kind(&map), BucketKind::List { .. }));
+    }
+
+    #[test]
+    fn entry_counts_words() {
+        let text = "the cat sat on the mat and the cat ran";
+        let mut counts = HashMap::new();
+        for word in text.split_whitespace() {
+            *counts.entry(word).or_insert(0) += 1;
+        }
+
+        assert_eq!(counts.len(), 7);
+        assert_eq!(counts.get("the"), Some(&3));
+        assert_eq!(counts.get("cat"), Some(&2));
+        assert_eq!(counts.get("ran"), Some(&1));
+        counts.check_invariants();
+    }
+
+    #[test]
+    fn entry() {
+        // every key in one bucket, so the entries go through a list and then
+        // a tree
+        let mut map = with_buckets(64, CollidingState);
+        for i in 0..20u64 {
+            match map.entry(i) {
+                Entry::Vacant(entry) => {
+                    assert_eq!(*entry.key(), i);
+                    *entry.insert(i) += 1;
+                }
+                Entry::Occupied(_) => panic!("{} isn't in the map yet", i),
+            }
+            map.check_invariants();
+        }
+        assert_eq!(map.len(), 20);
+
+        for i in 0..20u64 {
+            let entry = map.entry(i).and_modify(|v| *v *= 10);
+            assert_eq!(*entry.key(), i);
+            assert_eq!(*entry.or_insert_with(|| unreachable!()), (i + 1) * 10);
+        }
+        *map.entry(20).and_modify(|_| unreachable!()).or_default() += 7;
+        assert_eq!(map.get(&20), Some(&7));
+        assert_eq!(map.len(), 21);
+
+        if let Entry::Occupied(mut entry) = map.entry(3) {
+            assert_eq!(*entry.get(), 40);
+            assert_eq!(entry.insert(4), 40);
+            *entry.get_mut() += 1;
+            assert_eq!(*entry.into_mut(), 5);
+        } else {
+            panic!("3 is in the map");
+        }
+        if let Entry::Occupied(entry) = map.entry(5) {
+            assert_eq!(entry.remove_entry(), (5, 60));
+        } else {
+            panic!("5 is in the map");
+        }
+        if let Entry::Vacant(entry) = map.entry(5) {
+            assert_eq!(entry.into_key(), 5);
+        } else {
+            panic!("5 was removed");
+        }
+        assert_eq!(map.len(), 20);
+        assert_eq!(map.get(&5), None);
+        map.check_invariants();
+    }
+
+    #[test]
+    fn entry_or_insert_with_key() {
+        let mut map = HashMap::new();
+        map.insert("seven".to_string(), 7);
+        for word in &["one", "three", "seven", "one"] {
+            map.entry(word.to_string())
+                .or_insert_with_key(|key| key.len());
+        }
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get_str("one"), Some(&3));
+        assert_eq!(map.get_str("three"), Some(&5));
+        assert_eq!(map.get_str("seven"), Some(&7));
+    }
+
+    #[test]
+    fn entry_hashes_once() {
+        use std::collections::hash_map::DefaultHasher;
+
+        /// `RandomState` that counts how many hashes it finishes
+        struct CountingState {
+            inner: RandomState,
+            finishes: Rc<Cell<usize>>,
+        }
+
+        struct CountingHasher {
+            inner: DefaultHasher,
+            finishes: Rc<Cell<usize>>,
+        }
+
+        impl Hasher for CountingHasher {
+            fn finish(&self) -> u64 {
+                self.finishes.set(self.finishes.get() + 1);
+                self.inner.finish()
+            }
 
-        match &mut self.table[index] {
-            Entry::ListEntry(list) => {
-                let res = list.remove_entry(key);
-                if res.is_some() {
-                    self.len -= 1;
+            fn write(&mut self, bytes: &[u8]) {
+                self.inner.write(bytes);
+            }
+        }
+
+        impl BuildHasher for CountingState {
+            type Hasher = CountingHasher;
+
+            fn build_hasher(&self) -> CountingHasher {
+                CountingHasher {
+                    inner: self.inner.build_hasher(),
+                    finishes: self.finishes.clone(),
                 }
-                if list.is_empty() {
-                    self.table[index] = Entry::Empty;
+            }
+        }
+
+        let finishes = Rc::new(Cell::new(0));
+        let mut map = HashMap::with_hasher(CountingState {
+            inner: RandomState::new(),
+            finishes: finishes.clone(),
+        });
+
+        // vacant and occupied, through the table's first allocation and
+        // several resizes
+        for i in 0..1000 {
+            for _ in 0..2 {
+                let before = finishes.get();
+                map.entry(i).and_modify(|v| *v += 1).or_insert(0);
+                assert_eq!(finishes.get() - before, 1);
+            }
+        }
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.get(&7), Some(&1));
+    }
+
+    #[test]
+    fn entry_insert_rotating_tree_root() {
+        fn root<V>(map: &HashMap<u64, V, CollidingState>) -> Option<u64> {
+            match &map.table[0] {
+                Bucket::TreeEntry(tree) => tree.root_key().copied(),
+                _ => None,
+            }
+        }
+
+        // big enough that nothing resizes, every key in bucket 0
+        let mut map = with_buckets(1024, CollidingState);
+        let mut root_rotations = 0;
+        for i in 0..30u64 {
+            let before = root(&map);
+            let value = map.entry(i).or_insert(i);
+            *value += 100;
+            *value *= 2;
+            if before.is_some() && root(&map) != before {
+                root_rotations += 1;
+            }
+            assert_eq!(map.get(&i), Some(&((i + 100) * 2)));
+            map.check_invariants();
+        }
+        assert!(root_rotations > 0);
+        for i in 0..30u64 {
+            assert_eq!(map.get(&i), Some(&((i + 100) * 2)));
+        }
+    }
+
+    #[test]
+    fn entry_replace_key() {
+        let mut map = HashMap::new();
+        let foo = "foo".to_string();
+        let stored = foo.as_ptr();
+        map.insert(foo, 1);
+
+        let foo = "foo".to_string();
+        let passed = foo.as_ptr();
+        assert_ne!(stored, passed);
+        let old = match map.entry(foo) {
+            Entry::Occupied(entry) => entry.replace_key(),
+            Entry::Vacant(_) => panic!("foo should be in the map"),
+        };
+        assert_eq!(old, "foo");
+        assert_eq!(old.as_ptr(), stored);
+        let (key, &value) = map.get_key_value("foo").unwrap();
+        assert_eq!(key.as_ptr(), passed);
+        assert_eq!(value, 1);
+        assert_eq!(map.len(), 1);
+
+        // same through a tree bucket
+        let mut map = with_buckets(64, CollidingState);
+        for i in 0..20 {
+            map.insert(i.to_string(), i);
+        }
+        let key = "7".to_string();
+        let passed = key.as_ptr();
+        match map.entry(key) {
+            Entry::Occupied(entry) => assert_eq!(entry.replace_key(), "7"),
+            Entry::Vacant(_) => panic!("7 should be in the map"),
+        }
+        assert_eq!(map.get_key_value("7").unwrap().0.as_ptr(), passed);
+        assert_eq!(map.get("7"), Some(&7));
+        map.check_invariants();
+    }
+
+    #[test]
+    fn entry_debug() {
+        let mut map = HashMap::new();
+        map.insert("a", 1);
+
+        assert_eq!(
+            format!("{:?}", map.entry("a")),
+            r#"Entry(OccupiedEntry { key: "a", value: 1 })"#
+        );
+        assert_eq!(
+            format!("{:?}", map.entry("b")),
+            r#"Entry(VacantEntry("b"))"#
+        );
+    }
+
+    #[test]
+    fn vacant_entry_grows_before_inserting() {
+        let mut map = with_buckets(16, RandomState::new());
+        let threshold = super::resize_threshold(16) as u64;
+
+        // up to and across the boundary, each reference used before the
+        // next insert
+        for i in 0..threshold + 4 {
+            let value = match map.entry(i) {
+                Entry::Vacant(entry) => entry.insert(i),
+                Entry::Occupied(_) => panic!("{} isn't in the map yet", i),
+            };
+            *value += 100;
+            *value *= 2;
+            assert_eq!(*value, (i + 100) * 2);
+            let expected = if i + 1 < threshold { 16 } else { 32 };
+            assert_eq!(map.capacity(), expected);
+            assert_eq!(map.get(&i), Some(&((i + 100) * 2)));
+        }
+        for i in 0..threshold + 4 {
+            assert_eq!(map.get(&i), Some(&((i + 100) * 2)));
+        }
+        map.check_invariants();
+
+        // a key that is already there doesn't grow it, even at the boundary
+        let mut map = with_buckets(16, RandomState::new());
+        for i in 0..threshold - 1 {
+            map.insert(i, i);
+        }
+        *map.entry(0).or_insert(0) += 1;
+        assert_eq!(map.capacity(), 16);
+        *map.entry(threshold).or_insert(0) += 1;
+        assert_eq!(map.capacity(), 32);
+        assert_eq!(map.get(&threshold), Some(&1));
+    }
+
+    #[test]
+    fn entry_and_modify_or_default() {
+        let mut sums: HashMap<&str, i32> = HashMap::new();
+        sums.insert("b", 10);
+        for (key, n) in [("a", 1), ("b", 2), ("a", 3), ("c", -4)].iter() {
+            sums.entry(*key).and_modify_or_default(|sum| *sum += n);
+        }
+
+        assert_eq!(sums.len(), 3);
+        assert_eq!(sums.get("a"), Some(&4));
+        assert_eq!(sums.get("b"), Some(&12));
+        assert_eq!(sums.get("c"), Some(&-4));
+
+        let sum = sums.entry("d").and_modify_or_default(|sum| *sum += 5);
+        *sum *= 2;
+        assert_eq!(sums.get("d"), Some(&10));
+    }
+
+    #[test]
+    fn entry_or_insert_with_map() {
+        let mut map: HashMap<u32, Vec<u32>> = HashMap::new();
+        // one short of growing past 16 buckets
+        for i in 0..11 {
+            map.insert(i, Vec::new());
+        }
+        assert_eq!(map.capacity(), 16);
+
+        let nested = map.entry(11).or_insert_with_map(|map| {
+            assert_eq!(map.len(), 11);
+            assert!(!map.contains_key(&11));
+            Vec::with_capacity(map.capacity())
+        });
+        assert!(nested.capacity() >= 32);
+        nested.push(1);
+        assert_eq!(map.capacity(), 32);
+        assert_eq!(map.get(&11), Some(&vec![1]));
+        assert_eq!(map.len(), 12);
+
+        // an occupied entry doesn't call it
+        map.entry(11)
+            .or_insert_with_map(|_| panic!("11 is already in the map"))
+            .push(2);
+        assert_eq!(map.get(&11), Some(&vec![1, 2]));
+    }
+
+    #[test]
+    fn entry_or_try_insert_with() {
+        let mut map = HashMap::new();
+        map.insert("one".to_string(), 1);
+
+        let parse = |s: &str| s.parse::<i32>();
+        let one = map
+            .entry("one".to_string())
+            .or_try_insert_with(|| -> Result<i32, ()> { unreachable!() });
+        assert_eq!(one, Ok(&mut 1));
+
+        let two = map
+            .entry("two".to_string())
+            .or_try_insert_with(|| parse("2"));
+        *two.unwrap() += 10;
+        assert_eq!(map.get_str("two"), Some(&12));
+
+        let three = map
+            .entry("three".to_string())
+            .or_try_insert_with(|| parse("three"));
+        assert!(three.is_err());
+        assert_eq!(map.get_str("three"), None);
+        assert_eq!(map.len(), 2);
+        map.check_invariants();
+    }
+
+    #[test]
+    fn for_each_bucket_mut() {
+        let mut map = with_buckets(16, IdentityState);
+        map.table[1] = Bucket::ListEntry(LinkedList::new());
+        for &i in &[1u64, 17, 33, 2] {
+            map.insert(i, i + 1);
+        }
+
+        // IdentityState hashes keys to themselves
+        map.for_each_bucket_mut(|bucket| {
+            if let Bucket::ListEntry(list) = bucket {
+                let mut tree = AvlTree::new();
+                for (hash, k, v) in mem::replace(list, LinkedList::new()).into_iter_hashed() {
+                    tree.insert(hash, k, v);
                 }
-                res
+                *bucket = Bucket::TreeEntry(tree);
+            }
+        });
+
+        assert_eq!(map.stats().list_buckets, 0);
+        assert_eq!(map.stats().tree_buckets, 2);
+        for &i in &[1u64, 17, 33, 2] {
+            assert_eq!(map.get(&i), Some(&(i + 1)));
+        }
+    }
+
+    #[test]
+    fn remove_instrumented() {
+        let mut map = with_buckets(16, IdentityState);
+        map.table[2] = Bucket::TreeEntry(AvlTree::new());
+        map.insert(1u64, 1);
+        map.insert(2u64, 2);
+
+        assert_eq!(
+            map.remove_instrumented(&1),
+            (Some((1, 1)), RemovedFrom::List)
+        );
+        assert_eq!(map.remove_instrumented(&1), (None, RemovedFrom::Empty));
+        assert_eq!(map.remove_instrumented(&18).1, RemovedFrom::Tree);
+        assert_eq!(map.remove_instrumented(&2).1, RemovedFrom::Tree);
+
+        let mut lazy: HashMap<u64, u64> = HashMap::new();
+        assert_eq!(lazy.remove_instrumented(&1), (None, RemovedFrom::Inline));
+        lazy.insert(1, 1);
+        assert_eq!(
+            lazy.remove_instrumented(&1),
+            (Some((1, 1)), RemovedFrom::Inline)
+        );
+    }
+
+    #[test]
+    fn iter_buckets() {
+        let mut map = with_buckets(8, IdentityState);
+        map.table[2] = Bucket::TreeEntry(AvlTree::new());
+        for &i in &[1u64, 9, 2, 10, 18] {
+            map.insert(i, ());
+        }
+
+        let buckets: Vec<_> = map.iter_buckets().collect();
+        assert_eq!(buckets.len(), 8);
+        assert_eq!(buckets[1], (1, BucketKind::List { len: 2 }));
+        assert_eq!(buckets[2], (2, BucketKind::Tree { len: 3, height: 2 }));
+        let empty = buckets
+            .iter()
+            .filter(|(_, kind)| *kind == BucketKind::Empty)
+            .count();
+        assert_eq!(empty, 6);
+    }
+
+    #[test]
+    fn raw_entry() {
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            map.insert(i.to_string(), i);
+        }
+
+        let hash = map.hash_of("42");
+        assert_eq!(
+            map.raw_entry().from_hash(hash, |k| k == "42"),
+            map.get_key_value("42")
+        );
+        assert_eq!(
+            map.raw_entry().from_key_hashed_nocheck(hash, "42"),
+            map.get_key_value("42")
+        );
+        assert_eq!(map.raw_entry().from_key("42"), map.get_key_value("42"));
+        assert_eq!(map.raw_entry().from_hash(hash, |k| k == "43"), None);
+
+        let lazy: HashMap<String, i32> = HashMap::new();
+        assert_eq!(lazy.raw_entry().from_hash(hash, |_| true), None);
+    }
+
+    #[test]
+    fn raw_entry_checks_the_hash() {
+        // 1 and 17 share a list bucket, 1 and 33 a tree bucket
+        let mut lists = with_buckets(16, IdentityState);
+        lists.insert(1u64, "one");
+        lists.insert(17, "seventeen");
+        let mut trees = with_buckets(16, IdentityState);
+        trees.reserve_colliding(0);
+        trees.insert(1u64, "one");
+        trees.insert(33, "thirty-three");
+        assert_eq!(lists.stats().list_buckets, 1);
+        assert_eq!(trees.stats().tree_buckets, 1);
+
+        for (map, other) in [(&lists, 17), (&trees, 33)] {
+            assert_eq!(
+                map.raw_entry().from_hash(other, |_| true).unwrap().0,
+                &other
+            );
+            assert_eq!(map.raw_entry().from_hash(1, |_| true).unwrap().0, &1);
+            // a wrong hash finds nothing, even for a key that is there
+            assert_eq!(map.raw_entry().from_key_hashed_nocheck(1, &other), None);
+            assert_eq!(map.raw_entry().from_hash(49, |_| true), None);
+        }
+    }
+
+    #[test]
+    fn try_reserve_exact() {
+        let mut map = HashMap::new();
+        for i in 0..3 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.try_reserve_exact(0), Ok(()));
+        assert_eq!(map.try_reserve_exact(1), Ok(()));
+        // still fits inline
+        assert_eq!(map.capacity(), 0);
+
+        // 23 entries need 32 buckets, not the 64 doubling would go to
+        assert_eq!(map.try_reserve_exact(20), Ok(()));
+        assert_eq!(map.capacity(), 32);
+        for i in 3..23 {
+            assert!(!map.checked_insert(i, i).1);
+        }
+
+        assert!(map.try_reserve_exact(usize::MAX).is_err());
+        assert!(map.try_reserve_exact(isize::MAX as usize / 2).is_err());
+        assert_eq!(map.capacity(), 32);
+        assert_eq!(map.len(), 23);
+        for i in 0..23 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn get_each_mut() {
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+
+        let keys: Vec<i32> = (0..20).step_by(2).collect();
+        let key_refs: Vec<&i32> = keys.iter().collect();
+        for value in map.get_each_mut(&key_refs) {
+            *value.unwrap() += 1000;
+        }
+        for i in 0..100 {
+            let expected = if i < 20 && i % 2 == 0 { i + 1000 } else { i };
+            assert_eq!(map.get(&i), Some(&expected));
+        }
+
+        let missing = map.get_each_mut(&[&1, &200]);
+        assert!(missing[0].is_some());
+        assert!(missing[1].is_none());
+    }
+
+    #[test]
+    fn get_each_mut_inline() {
+        let mut map = HashMap::new();
+        for i in 0..4 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.capacity(), 0);
+
+        let values = map.get_each_mut(&[&3, &9, &0, &2]);
+        assert!(values[1].is_none());
+        for value in values.into_iter().flatten() {
+            *value += 10;
+        }
+        let mut entries = map.to_vec();
+        entries.sort_unstable();
+        assert_eq!(entries, [(0, 10), (1, 1), (2, 12), (3, 13)]);
+    }
+
+    #[test]
+    fn get_each_mut_list_bucket() {
+        let mut map = with_buckets(16, IdentityState);
+        for i in 0..8 {
+            map.insert(i * 16, i);
+        }
+        assert_eq!(map.stats().list_buckets, 1);
+
+        let keys: Vec<u64> = (0..8).map(|i| i * 16).collect();
+        let key_refs: Vec<&u64> = keys.iter().collect();
+        for value in map.get_each_mut(&key_refs) {
+            *value.unwrap() += 1000;
+        }
+        for i in 0..8 {
+            assert_eq!(map.get(&(i * 16)), Some(&(i + 1000)));
+        }
+    }
+
+    #[test]
+    fn get_each_mut_tree_bucket() {
+        let mut map = HashMap::with_hasher(CollidingState);
+        for i in 0..20 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.stats().tree_buckets, 1);
+
+        let keys: Vec<i32> = (0..20).collect();
+        let key_refs: Vec<&i32> = keys.iter().collect();
+        for value in map.get_each_mut(&key_refs) {
+            *value.unwrap() += 1000;
+        }
+        for i in 0..20 {
+            assert_eq!(map.get(&i), Some(&(i + 1000)));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate key passed to get_each_mut")]
+    fn get_each_mut_duplicate() {
+        let mut map = HashMap::new();
+        map.insert(1, 1);
+        map.insert(2, 2);
+        map.get_each_mut(&[&1, &2, &1]);
+    }
+
+    #[test]
+    fn resize_threshold() {
+        assert_eq!(super::resize_threshold(1), 0);
+        assert_eq!(super::resize_threshold(2), 1);
+        assert_eq!(super::resize_threshold(4), 3);
+        assert_eq!(super::resize_threshold(16), 12);
+        assert_eq!(
+            super::resize_threshold(1 << (usize::BITS - 1)),
+            3 << (usize::BITS - 3)
+        );
+
+        for shift in 0..12 {
+            let cap = 1 << shift;
+            let mut map = with_buckets(cap, RandomState::new());
+            assert_eq!(map.capacity(), cap);
+
+            let mut count = 0;
+            while !map.checked_insert(count, ()).1 {
+                count += 1;
+            }
+            // the insert that reaches the threshold is the one that grows
+            assert_eq!(count + 1, (cap * 3 / 4).max(1), "capacity {}", cap);
+        }
+    }
+
+    #[test]
+    fn insert_and_get() {
+        let mut map = HashMap::new();
+        let (v, old) = map.insert_and_get("a", vec![1]);
+        assert_eq!(old, None);
+        v.push(2);
+
+        let (v, old) = map.insert_and_get("a", vec![3]);
+        assert_eq!(old, Some(vec![1, 2]));
+        v.push(4);
+
+        assert_eq!(map.get("a"), Some(&vec![3, 4]));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn insert_or_update() {
+        let mut map = HashMap::new();
+        let mut inserts = 0;
+        let mut updates = 0;
+        for _ in 0..3 {
+            map.insert_or_update(
+                "a",
+                || {
+                    inserts += 1;
+                    1
+                },
+                |v| {
+                    updates += 1;
+                    *v += 1
+                },
+            );
+        }
+
+        assert_eq!((inserts, updates), (1, 2));
+        assert_eq!(map.get("a"), Some(&3));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn zero_sized_values() {
+        let mut set = HashMap::new();
+        for i in 0..100 {
+            assert_eq!(set.insert(i, ()), None);
+        }
+        assert_eq!(set.insert(7, ()), Some(()));
+        assert_eq!(set.len(), 100);
+        for i in 0..100 {
+            assert!(set.contains_key(&i));
+        }
+        assert!(!set.contains_key(&100));
+    }
+
+    #[test]
+    fn drain_filter_into() {
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            map.insert(i, i + 1);
+        }
+        let capacity = map.capacity();
+
+        let mut evens = HashMap::new();
+        map.drain_filter_into(|k, _| k % 2 == 0, &mut evens);
+
+        assert_eq!(map.len(), 50);
+        assert_eq!(evens.len(), 50);
+        assert_eq!(map.capacity(), capacity);
+        for i in 0..100 {
+            let (has, lacks) = if i % 2 == 0 {
+                (&evens, &map)
+            } else {
+                (&map, &evens)
+            };
+            assert_eq!(has.get(&i), Some(&(i + 1)));
+            assert_eq!(lacks.get(&i), None);
+        }
+    }
+
+    #[test]
+    fn try_drain_filter_into() {
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            map.insert(i, i + 1);
+        }
+
+        let mut evens = HashMap::new();
+        assert_eq!(
+            map.try_drain_filter_into(|k, _| k % 2 == 0, &mut evens),
+            Ok(())
+        );
+        assert_eq!(map.len(), 50);
+        assert_eq!(evens.len(), 50);
+        assert!(evens.iter().all(|(k, v)| k % 2 == 0 && *v == k + 1));
+
+        // nothing matches, so nothing has to be reserved
+        assert_eq!(map.try_drain_filter_into(|_, _| false, &mut evens), Ok(()));
+        assert_eq!(map.len(), 50);
+        assert_eq!(evens.len(), 50);
+    }
+
+    #[test]
+    fn drain_filter_into_predicate_panics() {
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+
+        let mut dest = HashMap::new();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            map.drain_filter_into(
+                |&k, _| {
+                    if k == 50 {
+                        panic!("predicate panicked");
+                    }
+                    true
+                },
+                &mut dest,
+            )
+        }));
+
+        // nothing moves until every entry has been looked at
+        assert!(res.is_err());
+        assert_eq!(map.len(), 100);
+        assert!(dest.is_empty());
+        map.check_invariants();
+    }
+
+    #[test]
+    fn drain_filter_into_hashes_only_moved_keys() {
+        thread_local! {
+            static HASHES: Cell<usize> = const { Cell::new(0) };
+        }
+
+        #[derive(PartialEq, Eq)]
+        struct CountedKey(u64);
+
+        impl Hash for CountedKey {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                HASHES.with(|hashes| hashes.set(hashes.get() + 1));
+                self.0.hash(state);
+            }
+        }
+
+        let mut map = HashMap::new();
+        for i in 0..100 {
+            map.insert(CountedKey(i), i);
+        }
+        HASHES.with(|hashes| hashes.set(0));
+
+        let mut evens = HashMap::new();
+        map.drain_filter_into(|k, _| k.0 % 2 == 0, &mut evens);
+
+        assert_eq!(HASHES.with(Cell::get), 50);
+        assert_eq!(map.len(), 50);
+        assert_eq!(evens.len(), 50);
+        map.check_invariants();
+        evens.check_invariants();
+    }
+
+    #[test]
+    #[cfg(not(feature = "probe-stats"))]
+    fn probe_counting_compiles_away() {
+        assert_eq!(mem::size_of::<Probes>(), 0);
+        assert_eq!(mem::size_of::<ProbeTotals>(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "probe-stats")]
+    fn probe_stats() {
+        let mut map = with_buckets(16, IdentityState).with_probe_stats();
+        assert_eq!(map.probe_stats(), ProbeStats::default());
+
+        // one list in bucket 1, kept in hash order: 1, 17, 33
+        for key in [33u64, 1, 17] {
+            map.insert(key, key);
+        }
+        assert_eq!(map.get(&1), Some(&1));
+        assert_eq!(map.get(&33), Some(&33));
+        assert_eq!(map.get(&49), None);
+        assert_eq!(map.get(&2), None);
+        assert_eq!(map.remove(&17), Some(17));
+        assert_eq!(
+            map.probe_stats(),
+            ProbeStats {
+                // the lookup in an empty bucket compares nothing
+                total_probes: 1 + 3 + 3 + 2,
+                total_lookups: 5,
+            }
+        );
+
+        // built in one go, so a perfect tree of height 4 in bucket 1
+        let keys: Vec<u64> = (0..15).map(|i| i * 64 + 1).collect();
+        let map = keys
+            .iter()
+            .map(|&k| (k, k))
+            .collect::<HashMap<_, _, IdentityState>>()
+            .with_probe_stats();
+        assert!(matches!(&map.table[1], Bucket::TreeEntry(tree) if tree.height() == 4));
+        for key in &keys {
+            assert!(map.contains_key(key));
+        }
+        assert_eq!(
+            map.probe_stats(),
+            ProbeStats {
+                total_probes: 1 + 2 * 2 + 4 * 3 + 8 * 4,
+                total_lookups: 15,
             }
+        );
+        // a miss goes all the way down
+        assert_eq!(map.get(&(15 * 64 + 1)), None);
+        assert_eq!(map.probe_stats().total_probes, 49 + 4);
 
-            Entry::TreeEntry(tree) => {
-                let res = tree.remove_entry(hash, key);
-                if res.is_some() {
-                    self.len -= 1;
-                }
-                if tree.is_empty() {
-                    self.table[index] = Entry::Empty;
-                }
-                res
+        // off unless asked for
+        let mut map = with_buckets(16, IdentityState);
+        map.insert(1, 1);
+        map.get(&1);
+        assert_eq!(map.probe_stats(), ProbeStats::default());
+    }
+
+    #[test]
+    fn lookup_cache() {
+        thread_local! {
+            static HASHES: Cell<usize> = const { Cell::new(0) };
+        }
+
+        #[derive(PartialEq, Eq, Debug)]
+        struct CountedKey(u64);
+
+        impl Hash for CountedKey {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                HASHES.with(|hashes| hashes.set(hashes.get() + 1));
+                self.0.hash(state);
             }
-            Entry::Empty => None,
         }
+        let hashes = || HASHES.with(|hashes| hashes.replace(0));
+
+        // one key per bucket, so a different key never hits by sharing one
+        let mut map = HashMap::with_hasher(IdentityState).with_lookup_cache();
+        for i in 0..100 {
+            map.insert(CountedKey(i), i);
+        }
+        hashes();
+
+        for _ in 0..10 {
+            assert_eq!(map.get(&CountedKey(7)), Some(&7));
+        }
+        *map.get_mut(&CountedKey(7)).unwrap() += 1;
+        assert!(map.contains_key(&CountedKey(7)));
+        assert_eq!(hashes(), 1);
+
+        // different keys in turn only miss, and missing keys aren't cached
+        for i in 0..200 {
+            let expected = match i {
+                7 => Some(&8),
+                0..=99 => Some(&i),
+                _ => None,
+            };
+            assert_eq!(map.get(&CountedKey(i)), expected);
+            assert_eq!(map.get(&CountedKey(i)), expected);
+        }
+        assert_eq!(hashes(), 300);
+
+        // inserting, removing and resizing all forget the cached key
+        map.get(&CountedKey(7));
+        map.insert(CountedKey(1000), 1000);
+        assert_eq!(map.get(&CountedKey(7)), Some(&8));
+        map.remove(&CountedKey(1000));
+        assert_eq!(map.get(&CountedKey(7)), Some(&8));
+        map.reserve(1000);
+        assert_eq!(map.get(&CountedKey(7)), Some(&8));
+        // the resize reuses the stored hashes
+        assert_eq!(hashes(), 6);
+        assert_eq!(map.remove(&CountedKey(7)), Some(8));
+        assert_eq!(map.get(&CountedKey(7)), None);
+
+        // every key shares the cached bucket and hash
+        let mut map = HashMap::with_hasher(CollidingState).with_lookup_cache();
+        for i in 0..20 {
+            map.insert(CountedKey(i), i);
+        }
+        assert_eq!(map.get(&CountedKey(3)), Some(&3));
+        hashes();
+        for i in 0..40 {
+            let expected = if i < 20 { Some(&i) } else { None };
+            assert_eq!(map.get(&CountedKey(i)), expected);
+        }
+        assert_eq!(hashes(), 20);
+
+        // without the cache every lookup hashes, once there's a table
+        let mut map = HashMap::new();
+        for i in 0..8 {
+            map.insert(CountedKey(i), i);
+        }
+        hashes();
+        for _ in 0..10 {
+            map.get(&CountedKey(7));
+        }
+        assert_eq!(hashes(), 10);
     }
 
-    fn resize(&mut self) {
-        // new capacity is twice as large
-        let new_cap = self.table.len() << 1;
+    #[test]
+    fn reserve() {
+        let mut map = HashMap::new();
+        map.reserve(0);
+        assert_eq!(map.capacity(), 0);
 
-        let mut v = Vec::new();
-        for _ in 0..new_cap {
-            v.push(Default::default());
+        map.reserve(12);
+        assert_eq!(map.capacity(), 32);
+        for i in 0..12 {
+            assert!(!map.checked_insert(i, i).1);
         }
 
-        // Swap in new table size
-        let mut old_table = v.into_boxed_slice();
-        std::mem::swap(&mut self.table, &mut old_table);
+        map.reserve(5);
+        assert_eq!(map.capacity(), 32);
+    }
 
-        // by value iterator
-        for entry in Vec::from(old_table) {
-            match entry {
-                Entry::ListEntry(list) => {
-                    for (k, v) in list {
-                        // ignores resizing
-                        self.insert_into_table(k, v);
-                    }
-                }
-                Entry::TreeEntry(tree) => {
-                    for (k, v) in tree {
-                        // ignores resizing
-                        self.insert_into_table(k, v);
-                    }
-                }
-                Entry::Empty => {}
-            }
+    #[test]
+    fn reserve_colliding() {
+        // a plain map builds a list first, then copies it into a tree
+        let mut plain = HashMap::with_hasher(CollidingState);
+        plain.reserve(100);
+        let allocations = ALLOCATIONS.with(Cell::get);
+        for i in 0..100u64 {
+            plain.insert(i, i);
         }
+        assert!(ALLOCATIONS.with(Cell::get) - allocations > 100);
+
+        let mut map = HashMap::with_hasher(CollidingState);
+        map.insert(1000u64, 1000);
+        map.reserve_colliding(100);
+        // the list already there is converted up front
+        assert!(matches!(map.table[0], Bucket::TreeEntry(_)));
+        let allocations = ALLOCATIONS.with(Cell::get);
+        for i in 0..100u64 {
+            map.insert(i, i);
+        }
+        // one node per entry, and nothing else
+        assert_eq!(ALLOCATIONS.with(Cell::get) - allocations, 100);
+        assert_eq!(map.get(&1000), Some(&1000));
+        map.check_invariants();
+
+        // spread out keys get trees too, through resizes and every insert path
+        let mut map = with_buckets(16, IdentityState);
+        map.reserve_colliding(0);
+        for i in 0..100u64 {
+            map.insert(i, i);
+        }
+        *map.get_or_insert_with(100, || 0) += 1;
+        map.entry(101).or_insert(0);
+        map.extend((102..110).map(|i| (i, i)));
+        assert!(map.capacity() > 16);
+        let stats = map.stats();
+        assert_eq!(stats.list_buckets, 0);
+        assert_eq!(stats.tree_buckets, map.capacity() - stats.empty_buckets);
+        assert_eq!(map.len(), 110);
+        map.check_invariants();
     }
 
-    fn insert_into_table(&mut self, key: K, value: V) -> Option<V> {
-        let hash = self.hash(&key);
-        let index = self.hash_index(hash);
+    #[test]
+    #[should_panic(expected = "map at maximum capacity")]
+    fn reserve_overflow() {
+        let mut map = HashMap::new();
+        map.insert(0, 0);
+        map.reserve(usize::MAX);
+    }
 
-        match &mut self.table[index] {
-            Entry::ListEntry(list) => list.insert(key, value),
-            Entry::TreeEntry(tree) => tree.insert(hash, key, value),
-            Entry::Empty => {
-                let mut entry = AvlTree::new();
-                entry.insert(hash, key, value);
-                self.table[index] = Entry::TreeEntry(entry);
-                None
+    #[test]
+    fn extend_from_slice() {
+        use std::sync::{Arc, Mutex};
+
+        let pairs: Vec<(u32, u32)> = (0..1000).map(|i| (i, i * 2)).collect();
+        let resizes = Arc::new(Mutex::new(Vec::new()));
+        let mut map = HashMap::new();
+        let log = resizes.clone();
+        map.set_resize_hook(Box::new(move |old, new| {
+            log.lock().unwrap().push((old, new));
+        }));
+
+        map.extend_from_slice(&pairs);
+
+        assert_eq!(*resizes.lock().unwrap(), vec![(0, 2048)]);
+        assert_eq!(map.len(), 1000);
+        for &(k, v) in &pairs {
+            assert_eq!(map.get(&k), Some(&v));
+        }
+    }
+
+    #[test]
+    fn bucket_invariants_under_churn() {
+        // xorshift so the sequence is the same on every run
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let mut map = HashMap::with_capacity_and_hasher(0, IdentityState);
+        for _ in 0..10_000 {
+            let r = next();
+            let key = r % 512;
+            if r & (1 << 32) == 0 {
+                map.insert(key, r);
+            } else {
+                map.remove(&key);
             }
+            map.check_invariants();
         }
     }
-}
 
-impl<K: Hash + Eq, V> FromIterator<(K, V)> for HashMap<K, V> {
-    // TODO: use sizehint?
-    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
-        let mut map = HashMap::new();
+    /// Randomly inserts and removes `steps` keys picked by `key_of`, checking
+    /// the buckets and every tree's balance after each step, and that every
+    /// value is dropped exactly once
+    fn churn<S: BuildHasher>(
+        mut map: HashMap<u64, DropCounter, S>,
+        seed: u64,
+        steps: u64,
+        key_of: fn(u64) -> u64,
+    ) {
+        let drops = Rc::new(Cell::new(0));
+        let mut made = 0;
+        let mut keys = std::collections::HashSet::new();
+        let mut rng = XorShift(seed);
 
-        for (k, v) in iter {
-            map.insert(k, v);
+        for step in 0..steps {
+            let r = rng.next_u64();
+            let key = key_of(r);
+            // a few more inserts than removes so the buckets fill up
+            if (r >> 40) % 8 < 5 {
+                made += 1;
+                map.insert(key, DropCounter(drops.clone()));
+                keys.insert(key);
+            } else {
+                let removed = map.remove(&key).is_some();
+                assert_eq!(removed, keys.remove(&key), "step {}", step);
+            }
+
+            map.check_invariants();
+            assert_eq!(map.len(), keys.len());
+            for bucket in map.table.iter() {
+                if let Bucket::TreeEntry(tree) = bucket {
+                    tree.check_balanced();
+                }
+            }
         }
 
-        map
+        for key in &keys {
+            assert!(map.contains_key(key));
+        }
+        drop(map);
+        assert_eq!(drops.get(), made);
     }
-}
 
-#[cfg(test)]
-mod tests {
+    /// `MAPS_CHURN_SEED` and `MAPS_CHURN_STEPS` override the seed and the
+    /// number of steps, for longer runs
+    #[test]
+    fn churn_keeps_trees_balanced_without_leaking() {
+        let seed = env_or("MAPS_CHURN_SEED", 0x2545_f491_4f6c_dd1d);
+        let steps = env_or("MAPS_CHURN_STEPS", if cfg!(miri) { 200 } else { 4000 });
 
-    use super::*;
+        // four crowded buckets with distinct hashes, whatever the capacity
+        churn(with_buckets(16, IdentityState), seed, steps, |r| {
+            r & 3 | ((r >> 8) % 128) << 20
+        });
+        // one bucket where every hash is equal
+        churn(with_buckets(16, CollidingState), seed, steps, |r| {
+            (r >> 8) % 100
+        });
+    }
 
     #[test]
-    fn empty_len() {
-        let map: HashMap<(), ()> = HashMap::new();
-        assert_eq!(map.len(), 0);
+    fn into_iter_sorted() {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut map = HashMap::new();
+        for _ in 0..500 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            map.insert(state % 10_000, state);
+        }
+        let len = map.len();
+
+        let entries: Vec<_> = map.into_iter_sorted().collect();
+        assert_eq!(entries.len(), len);
+        assert!(entries.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(entries.iter().all(|&(k, v)| v % 10_000 == k));
     }
 
     #[test]
-    fn get_non_existent_key() {
-        let map: HashMap<(), ()> = HashMap::new();
-        assert_eq!(map.get(&()), None);
+    fn resize_splits_tree_buckets_into_lists() {
+        // IdentityState hashes keys to themselves, so every key starts in
+        // bucket 0 and ends up spread over 4 buckets once there are 64
+        let mut map = with_buckets(16, IdentityState);
+        let mut capacities = Vec::new();
+        for i in 0..30u64 {
+            map.insert(i * 16, i);
+            if capacities.last() != Some(&map.capacity()) {
+                capacities.push(map.capacity());
+            }
+            if i == 10 {
+                assert_eq!(map.stats().tree_buckets, 1);
+            }
+            map.check_invariants();
+        }
+
+        assert_eq!(capacities, vec![16, 32, 64]);
+        assert_eq!(map.len(), 30);
+        let stats = map.stats();
+        assert_eq!(stats.tree_buckets, 0);
+        assert_eq!(stats.list_buckets, 4);
+        for i in 0..30u64 {
+            assert_eq!(map.get(&(i * 16)), Some(&i));
+        }
+        assert_eq!(map.get(&(30 * 16)), None);
     }
 
     #[test]
-    fn insert_one() {
+    fn resize_does_not_rehash() {
+        thread_local! {
+            static HASHES: Cell<usize> = const { Cell::new(0) };
+        }
+
+        #[derive(PartialEq, Eq)]
+        struct CountedKey(u64);
+
+        impl Hash for CountedKey {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                HASHES.with(|hashes| hashes.set(hashes.get() + 1));
+                self.0.hash(state);
+            }
+        }
+
         let mut map = HashMap::new();
-        assert_eq!(map.insert(1, 2), None);
-        println!("{:?}", map.table);
-        assert_eq!(map.get(&1), Some(&2));
-        assert_eq!(map.len(), 1);
+        for i in 0..100_000 {
+            map.insert(CountedKey(i), i);
+        }
+        assert!(map.capacity() >= 131_072);
+        // one hash per insert, none for the resizes in between
+        assert_eq!(HASHES.with(Cell::get), 100_000);
     }
 
     #[test]
-    fn insert_and_replace_one() {
-        let mut map = HashMap::new();
-        assert_eq!(map.insert(1, 2), None);
-        println!("{:?}", map.table);
-        assert_eq!(map.get(&1), Some(&2));
-        assert_eq!(map.insert(1, 3), Some(2));
-        println!("{:?}", map.table);
-        assert_eq!(map.get(&1), Some(&3));
-        assert_eq!(map.len(), 1);
+    fn shrink_to_fit_does_not_rehash() {
+        use std::collections::hash_map::DefaultHasher;
+
+        struct CountingState(Rc<Cell<usize>>);
+
+        impl BuildHasher for CountingState {
+            type Hasher = DefaultHasher;
+
+            fn build_hasher(&self) -> DefaultHasher {
+                self.0.set(self.0.get() + 1);
+                DefaultHasher::new()
+            }
+        }
+
+        let hashes = Rc::new(Cell::new(0));
+        let mut map = with_buckets(1024, CountingState(hashes.clone()));
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+
+        hashes.set(0);
+        map.shrink_to_fit();
+        assert_eq!(hashes.get(), 0);
+        assert_eq!(map.capacity(), 256);
+        for i in 0..100 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
     }
 
     #[test]
-    fn insert_many() {
+    fn borrowed_str_lookups() {
+        use std::sync::Arc;
+
+        let words = ["one", "two", "three"];
+
+        let boxed: HashMap<Box<str>, i32> =
+            words.iter().map(|&w| (w.into(), w.len() as i32)).collect();
+        let rc: HashMap<Rc<str>, i32> = words.iter().map(|&w| (w.into(), w.len() as i32)).collect();
+        let arc: HashMap<Arc<str>, i32> =
+            words.iter().map(|&w| (w.into(), w.len() as i32)).collect();
+
+        for &w in &words {
+            let len = w.len() as i32;
+            assert_eq!(boxed.get(w), Some(&len));
+            assert_eq!(rc.get(w), Some(&len));
+            assert_eq!(arc.get(w), Some(&len));
+            // the borrowed form hashes the same as the owned one
+            assert_eq!(boxed.hash_of(w), boxed.hash_of(&Box::<str>::from(w)));
+        }
+        assert!(!boxed.contains_key("four"));
+        assert!(!rc.contains_key("four"));
+        assert!(!arc.contains_key("four"));
+    }
+
+    #[test]
+    fn str_lookups() {
+        let mut map: HashMap<String, i32> = HashMap::new();
+        map.insert("one".to_string(), 1);
+        map.insert("two".to_string(), 2);
+
+        assert_eq!(map.get_str("one"), Some(&1));
+        assert!(map.contains_str("two"));
+        assert!(!map.contains_str("three"));
+        assert_eq!(map.remove_str("three"), None);
+        assert_eq!(map.remove_str("one"), Some(1));
+        assert!(!map.contains_str("one"));
+    }
+
+    #[test]
+    fn retain() {
         let mut map = HashMap::new();
         for i in 0..1000 {
-            assert_eq!(map.insert(i, i + 1), None);
+            map.insert(i, i);
         }
+        assert!(map.capacity() > DEFAULT_CAPACITY);
+
+        map.retain(|&k, v| {
+            *v += 1;
+            k % 2 == 0
+        });
+
+        assert_eq!(map.len(), 500);
+        map.check_invariants();
         for i in 0..1000 {
-            assert_eq!(map.get(&i), Some(&(i + 1)));
+            let expected = if i % 2 == 0 { Some(i + 1) } else { None };
+            assert_eq!(map.get(&i).copied(), expected);
         }
     }
 
     #[test]
-    fn insert_and_replace_many() {
+    fn retain_destructor_panics() {
+        struct PanicOnDrop(u64);
+
+        impl Drop for PanicOnDrop {
+            fn drop(&mut self) {
+                if self.0 == 1 {
+                    panic!("drop panicked");
+                }
+            }
+        }
+
+        let mut map = HashMap::with_hasher(CollidingState);
+        for i in 0..20 {
+            map.insert(i, PanicOnDrop(i));
+        }
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| map.retain(|&k, _| k >= 5)));
+
+        assert!(res.is_err());
+        assert!(!map.contains_key(&1));
+        map.check_invariants();
+        for i in 5..20 {
+            assert_eq!(map.get(&i).map(|v| v.0), Some(i));
+        }
+    }
+
+    #[test]
+    fn retain_while() {
         let mut map = HashMap::new();
-        for i in 0..1000 {
-            assert_eq!(map.insert(i, i + 1), None);
+        for i in 0..100 {
+            map.insert(i, i);
         }
-        for i in 0..1000 {
-            assert_eq!(map.get(&i), Some(&(i + 1)));
+
+        let mut visited = Vec::new();
+        map.retain_while(|&k, v| {
+            if visited.len() == 30 {
+                return ControlFlow::Break(());
+            }
+            visited.push(k);
+            *v += 1000;
+            ControlFlow::Continue(k % 2 == 0)
+        });
+
+        let removed = visited.iter().filter(|&&k| k % 2 == 1).count();
+        assert_eq!(map.len(), 100 - removed);
+        map.check_invariants();
+        for i in 0..100 {
+            match (visited.contains(&i), i % 2 == 0) {
+                (true, true) => assert_eq!(map.get(&i), Some(&(i + 1000))),
+                (true, false) => assert_eq!(map.get(&i), None),
+                (false, _) => assert_eq!(map.get(&i), Some(&i)),
+            }
         }
+    }
 
-        for i in 0..1000 {
-            assert_eq!(map.insert(i, i + i + 1), Some(i + 1));
+    #[test]
+    fn retain_while_predicate_panics() {
+        // buckets 0, 1 and 2 hold three keys each
+        let mut map = with_buckets(16, IdentityState);
+        for i in 0..9u64 {
+            map.insert(i % 3 + i / 3 * 16, i);
         }
-        for i in 0..1000 {
-            assert_eq!(map.get(&i), Some(&(i + i + 1)));
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            map.retain_while(|&k, _| {
+                if k % 16 == 1 {
+                    panic!("predicate panicked");
+                }
+                ControlFlow::Continue(false)
+            })
+        }));
+
+        // bucket 0 was finished, bucket 1 is left as it was
+        assert!(res.is_err());
+        assert_eq!(map.len(), 6);
+        map.check_invariants();
+        for i in 0..9u64 {
+            let key = i % 3 + i / 3 * 16;
+            assert_eq!(map.contains_key(&key), key % 16 != 0);
         }
     }
 
     #[test]
-    fn insert_and_remove_one() {
-        let mut map = HashMap::new();
-        assert_eq!(map.insert(1, 2), None);
-        println!("{:?}", map.table);
-        assert_eq!(map.get(&1), Some(&2));
-        assert_eq!(map.len(), 1);
-        assert_eq!(map.remove(&1), Some(2));
-        println!("{:?}", map.table);
-        assert_eq!(map.get(&1), None);
-        assert_eq!(map.len(), 0);
+    fn retain_while_keeping_everything_leaves_buckets_alone() {
+        let mut map = HashMap::with_hasher(CollidingState);
+        for i in 0..20 {
+            map.insert(i, i);
+        }
+        let before: Vec<*const i32> = (0..20).map(|i| map.get(&i).unwrap() as *const _).collect();
+
+        map.retain_while(|_, _| ControlFlow::Continue(true));
+
+        let after: Vec<*const i32> = (0..20).map(|i| map.get(&i).unwrap() as *const _).collect();
+        assert_eq!(before, after);
     }
 
     #[test]
-    fn insert_and_remove_many() {
-        let mut map = HashMap::new();
-        for i in 0..1000 {
-            assert_eq!(map.insert(i, i + 1), None);
+    fn try_clone() {
+        let mut map = HashMap::with_seed(1, 2);
+        for i in 0..100 {
+            map.insert(i, i.to_string());
         }
-        for i in 0..1000 {
-            assert_eq!(map.get(&i), Some(&(i + 1)));
+
+        let clone = map.try_clone().unwrap();
+        assert_eq!(clone, map);
+        assert_eq!(clone.len(), map.len());
+        assert_eq!(clone.capacity(), map.capacity());
+        assert_eq!(clone.bucket_index_of(&7), map.bucket_index_of(&7));
+        clone.check_invariants();
+
+        // no real map is big enough to fail, so ask for a table that is
+        assert!(map.try_clone_with_capacity(usize::MAX).is_err());
+        assert_eq!(map.len(), 100);
+        assert_eq!(map.get(&7).map(String::as_str), Some("7"));
+    }
+
+    #[test]
+    fn try_clone_reuses_the_hashes() {
+        use std::collections::hash_map::DefaultHasher;
+
+        #[derive(Clone)]
+        struct CountingState(Rc<Cell<usize>>);
+
+        impl BuildHasher for CountingState {
+            type Hasher = DefaultHasher;
+
+            fn build_hasher(&self) -> DefaultHasher {
+                self.0.set(self.0.get() + 1);
+                DefaultHasher::new()
+            }
         }
 
-        for i in 0..1000 {
-            assert_eq!(map.remove(&i), Some(i + 1));
+        let hashes = Rc::new(Cell::new(0));
+        let mut lists = HashMap::with_hasher(CountingState(hashes.clone()));
+        let mut trees = HashMap::with_hasher(CountingState(hashes.clone()));
+        trees.reserve_colliding(0);
+        for i in 0..100 {
+            lists.insert(i, i);
+            trees.insert(i, i);
         }
-        for i in 0..1000 {
-            assert_eq!(map.get(&i), None);
+
+        for map in [&lists, &trees] {
+            hashes.set(0);
+            let clone = map.try_clone().unwrap();
+            assert_eq!(hashes.get(), 0);
+            for i in 0..100 {
+                assert_eq!(clone.bucket_index_of(&i), map.bucket_index_of(&i));
+            }
+            clone.check_invariants();
         }
     }
 
     #[test]
-    fn from_iter() {
-        let map: HashMap<_, _> = (0..1000).map(|i| (i, i + 1)).collect();
+    fn treeify_builds_balanced_without_rotating() {
+        use crate::avl_tree::ROTATIONS;
 
-        for i in 0..1000 {
+        let entries: Vec<_> = (0..10_000u64).map(|i| (i * 16, i, i + 1)).collect();
+        let mut bucket = Bucket::ListEntry(LinkedList::from_unique_sorted(entries));
+
+        let before = ROTATIONS.with(Cell::get);
+        bucket.treeify_if_full();
+        assert_eq!(ROTATIONS.with(Cell::get), before);
+
+        match &bucket {
+            Bucket::TreeEntry(tree) => {
+                tree.check_balanced();
+                assert_eq!(tree.len(), 10_000);
+                // the minimal height for 10_000 nodes
+                assert_eq!(tree.height(), 14);
+                for i in 0..10_000u64 {
+                    assert_eq!(tree.get(i * 16, &i, &mut Probes::default()), Some(&(i + 1)));
+                }
+            }
+            _ => panic!("a full list should become a tree"),
+        }
+    }
+
+    #[test]
+    fn shrink_to_fit_single_bucket() {
+        let mut map = with_buckets(1024, CollidingState);
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+        for i in (0..100).step_by(2) {
+            assert_eq!(map.remove(&i), Some(i));
+        }
+        assert_eq!(map.stats().empty_buckets, 1023);
+
+        map.shrink_to_fit();
+        assert_eq!(map.capacity(), 128);
+        // sized by len alone, so shrinking again doesn't go any further
+        map.shrink_to_fit();
+        assert_eq!(map.capacity(), 128);
+
+        assert_eq!(map.len(), 50);
+        assert_eq!(map.stats().empty_buckets, 127);
+        map.check_invariants();
+        match &map.table[0] {
+            Bucket::TreeEntry(tree) => tree.check_balanced(),
+            _ => panic!("the shared bucket should still be a tree"),
+        }
+        for i in 0..100 {
+            let expected = if i % 2 == 1 { Some(&i) } else { None };
+            assert_eq!(map.get(&i), expected);
+        }
+    }
+
+    #[test]
+    fn compact() {
+        // crowded buckets with distinct hashes, churned so the trees are
+        // balanced but not minimal height
+        let mut map = with_buckets(64, IdentityState);
+        let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+        let mut keys = std::collections::HashSet::new();
+        for _ in 0..3000 {
+            let r = rng.next_u64();
+            let key = r & 3 | ((r >> 8) % 200) << 20;
+            if (r >> 40).is_multiple_of(3) {
+                map.remove(&key);
+                keys.remove(&key);
+            } else {
+                map.insert(key, key + 1);
+                keys.insert(key);
+            }
+        }
+        fn minimal_height(len: usize) -> usize {
+            (usize::BITS - len.leading_zeros()) as usize
+        }
+        let taller = map.table.iter().any(|bucket| match bucket {
+            Bucket::TreeEntry(tree) => tree.height() > minimal_height(tree.len()),
+            _ => false,
+        });
+        assert!(taller);
+        let capacity = map.capacity();
+        let len = map.len();
+        let before = map.stats();
+
+        map.compact();
+
+        assert_eq!(map.capacity(), capacity);
+        assert_eq!(map.len(), len);
+        assert_eq!(map.stats().tree_buckets, before.tree_buckets);
+        assert_eq!(map.stats().list_buckets, before.list_buckets);
+        map.check_invariants();
+        for bucket in map.table.iter() {
+            if let Bucket::TreeEntry(tree) = bucket {
+                tree.check_balanced();
+                assert_eq!(tree.height(), minimal_height(tree.len()));
+            }
+        }
+        for key in &keys {
+            assert_eq!(map.get(key), Some(&(key + 1)));
+        }
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let mut map = with_buckets(1024, RandomState::new());
+        for i in 0..10 {
+            map.insert(i, i + 1);
+        }
+        assert_eq!(map.capacity(), 1024);
+
+        map.shrink_to_fit();
+        assert_eq!(map.capacity(), 16);
+        assert_eq!(map.len(), 10);
+        for i in 0..10 {
             assert_eq!(map.get(&i), Some(&(i + 1)));
         }
     }
+
+    mod capacity_contract {
+        use super::*;
+
+        #[test]
+        fn with_capacity_is_minimal() {
+            for n in 0..=inline_map::CAPACITY {
+                assert_eq!(HashMap::<u32, u32>::with_capacity(n).capacity(), 0);
+            }
+            for n in inline_map::CAPACITY + 1..1000 {
+                let cap = HashMap::<u32, u32>::with_capacity(n).capacity();
+                assert!(cap.is_power_of_two());
+                assert!(n < crate::hashmap::resize_threshold(cap), "{}", n);
+                assert!(n >= crate::hashmap::resize_threshold(cap / 2), "{}", n);
+            }
+        }
+
+        #[test]
+        fn with_capacity_never_resizes_early() {
+            for n in 1..300 {
+                let mut map = HashMap::with_capacity(n);
+                for i in 0..n {
+                    assert!(!map.checked_insert(i, i).1, "{} of {}", i, n);
+                }
+            }
+        }
+
+        #[test]
+        fn resizes_exactly_at_threshold() {
+            let mut map = HashMap::new();
+            let mut resized_at = Vec::new();
+            for i in 0..3000 {
+                let before = map.capacity();
+                if map.checked_insert(i, i).1 && before > 0 {
+                    resized_at.push((map.len(), before));
+                }
+            }
+
+            for &(len, cap) in &resized_at {
+                assert_eq!(len, crate::hashmap::resize_threshold(cap));
+            }
+            let caps: Vec<_> = resized_at.iter().map(|&(_, cap)| cap).collect();
+            assert_eq!(caps, vec![16, 32, 64, 128, 256, 512, 1024, 2048]);
+        }
+
+        #[test]
+        fn reserve_never_resizes_early() {
+            for &len in &[0, 1, 11, 12, 100] {
+                for additional in 0..64 {
+                    let mut map = HashMap::new();
+                    for i in 0..len {
+                        map.insert(i, i);
+                    }
+
+                    map.reserve(additional);
+                    let cap = map.capacity();
+                    for i in len..len + additional {
+                        assert!(!map.checked_insert(i, i).1);
+                    }
+                    assert_eq!(map.capacity(), cap);
+                }
+            }
+        }
+    }
 }
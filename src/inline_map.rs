@@ -0,0 +1,406 @@
+use std::borrow::Borrow;
+use std::mem;
+use std::ops::ControlFlow;
+
+use crate::probes::Probes;
+
+/// Most entries a map keeps inline before it allocates a table
+pub(crate) const CAPACITY: usize = 4;
+
+/// The entries of a map too small to need a table, kept in the map itself
+/// and found by comparing keys one after another. The first `len` slots
+/// are filled, in the order the entries went in, and the rest are `None`.
+/// Each entry keeps its hash, so moving it into a table doesn't hash the
+/// key again.
+#[derive(Debug)]
+pub(crate) struct InlineMap<K, V> {
+    entries: [Option<(u64, K, V)>; CAPACITY],
+    len: usize,
+}
+
+// derive would needlessly require K: Default and V: Default
+impl<K, V> Default for InlineMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> InlineMap<K, V> {
+    pub fn new() -> Self {
+        Self {
+            entries: Default::default(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            entries: self.entries.iter(),
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut {
+            entries: self.entries.iter_mut(),
+        }
+    }
+
+    /// Borrows every entry along with its hash, so the entries can be
+    /// copied elsewhere without hashing the keys again
+    pub fn iter_hashed(&self) -> IterHashed<'_, K, V> {
+        IterHashed(self.iter())
+    }
+
+    /// Consumes the entries yielding `(hash, key, value)`, so they can be
+    /// moved elsewhere without hashing the keys again
+    pub fn into_iter_hashed(self) -> IntoIterHashed<K, V> {
+        IntoIterHashed(self.into_iter())
+    }
+
+    /// Adds an entry whose key isn't here yet
+    ///
+    /// # Panics
+    ///
+    /// If every slot is taken.
+    fn push(&mut self, hash: u64, key: K, value: V) -> &mut V {
+        assert!(self.len < CAPACITY, "inline map full");
+        let slot = &mut self.entries[self.len];
+        self.len += 1;
+        &mut slot.insert((hash, key, value)).2
+    }
+
+    fn entry(&self, index: usize) -> &(u64, K, V) {
+        self.entries[index]
+            .as_ref()
+            .expect("inline entries are packed")
+    }
+
+    fn entry_mut(&mut self, index: usize) -> &mut (u64, K, V) {
+        self.entries[index]
+            .as_mut()
+            .expect("inline entries are packed")
+    }
+
+    /// Value in slot `index`, as found by `position`
+    pub fn value_at(&self, index: usize) -> &V {
+        &self.entry(index).2
+    }
+
+    pub fn value_at_mut(&mut self, index: usize) -> &mut V {
+        &mut self.entry_mut(index).2
+    }
+
+    /// The values in slot order, each borrowed on its own, so any of them
+    /// can be handed out at once
+    pub fn values_mut(&mut self) -> Vec<&mut V> {
+        self.iter_mut().map(|(_, v)| v).collect()
+    }
+
+    /// Removes the entry in slot `index`, as found by `position`
+    pub fn remove_entry_at(&mut self, index: usize) -> (K, V) {
+        let (_, k, v) = self.remove_at(index);
+        (k, v)
+    }
+
+    /// Takes out the entry at `index`, moving the ones after it up a slot
+    fn remove_at(&mut self, index: usize) -> (u64, K, V) {
+        let entry = self.entries[index]
+            .take()
+            .expect("inline entries are packed");
+        self.entries[index..self.len].rotate_left(1);
+        self.len -= 1;
+        entry
+    }
+
+    /// `Bucket::mark` for the inline entries, with the flags in an array so
+    /// marking a small map doesn't allocate. Entries `f` didn't reach, and
+    /// slots past `len`, are marked true.
+    pub fn mark<F>(&mut self, f: &mut F) -> ([bool; CAPACITY], ControlFlow<()>)
+    where
+        F: FnMut(&K, &mut V) -> ControlFlow<(), bool>,
+    {
+        let mut keep = [true; CAPACITY];
+        for (flag, (k, v)) in keep.iter_mut().zip(self.iter_mut()) {
+            match f(k, v) {
+                ControlFlow::Continue(b) => *flag = b,
+                ControlFlow::Break(()) => return (keep, ControlFlow::Break(())),
+            }
+        }
+        (keep, ControlFlow::Continue(()))
+    }
+
+    /// `Bucket::remove_marked` for the inline entries. Each entry marked
+    /// false is taken out before it is handed to `removed`, so if that
+    /// panics the entries not reached yet are still here.
+    pub fn remove_marked<I, R>(&mut self, keep: I, len: &mut usize, mut removed: R)
+    where
+        I: IntoIterator<Item = bool>,
+        R: FnMut(K, V),
+    {
+        let mut keep = keep.into_iter();
+        let mut index = 0;
+        while index < self.len {
+            if keep.next().unwrap_or(true) {
+                index += 1;
+            } else {
+                // the next entry moves up into `index`
+                let (_, k, v) = self.remove_at(index);
+                *len -= 1;
+                removed(k, v);
+            }
+        }
+    }
+}
+
+impl<K: Eq, V> InlineMap<K, V> {
+    /// Slot of the entry stored under `key`, comparing it against each key
+    /// in turn
+    pub fn position<Q>(&self, key: &Q, probes: &mut Probes) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.iter().position(|(k, _)| {
+            probes.step();
+            k.borrow() == key
+        })
+    }
+
+    pub fn get<Q>(&self, key: &Q, probes: &mut Probes) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.get_key_value(key, probes).map(|(_, v)| v)
+    }
+
+    pub fn get_key_value<Q>(&self, key: &Q, probes: &mut Probes) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let (_, k, v) = self.entry(self.position(key, probes)?);
+        Some((k, v))
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q, probes: &mut Probes) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let index = self.position(key, probes)?;
+        Some(&mut self.entry_mut(index).2)
+    }
+
+    /// Finds the entry stored under `hash` whose key satisfies `is_match`
+    pub fn find<F: FnMut(&K) -> bool>(&self, hash: u64, mut is_match: F) -> Option<(&K, &V)> {
+        self.iter_hashed()
+            .find(|&(h, k, _)| h == hash && is_match(k))
+            .map(|(_, k, v)| (k, v))
+    }
+
+    /// Inserts the entry, or replaces the value of an equal key, keeping the
+    /// key already stored
+    ///
+    /// # Panics
+    ///
+    /// If the key is new and every slot is taken.
+    pub fn insert(&mut self, hash: u64, key: K, value: V) -> Option<V> {
+        match self.position(&key, &mut Probes::default()) {
+            Some(index) => Some(mem::replace(self.value_at_mut(index), value)),
+            None => {
+                self.push(hash, key, value);
+                None
+            }
+        }
+    }
+
+    /// Returns the value stored under `key`, inserting `f()` first if there
+    /// isn't one, along with whether `f` was called
+    ///
+    /// # Panics
+    ///
+    /// If the key is new and every slot is taken.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(
+        &mut self,
+        hash: u64,
+        key: K,
+        f: F,
+    ) -> (&mut V, bool) {
+        match self.position(&key, &mut Probes::default()) {
+            Some(index) => (self.value_at_mut(index), false),
+            None => (self.push(hash, key, f()), true),
+        }
+    }
+
+    pub fn replace_key(&mut self, key: K) -> Option<K> {
+        let index = self.position(&key, &mut Probes::default())?;
+        Some(mem::replace(&mut self.entry_mut(index).1, key))
+    }
+
+    pub fn remove_entry<Q>(&mut self, key: &Q, probes: &mut Probes) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let index = self.position(key, probes)?;
+        Some(self.remove_entry_at(index))
+    }
+}
+
+pub(crate) struct Iter<'a, K, V> {
+    entries: std::slice::Iter<'a, Option<(u64, K, V)>>,
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    /// Like `next`, but also hands back the hash stored with the entry
+    fn next_hashed(&mut self) -> Option<(u64, &'a K, &'a V)> {
+        // the filled slots come first, so the first empty one ends the walk
+        let (hash, key, value) = self.entries.next()?.as_ref()?;
+        Some((*hash, key, value))
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_hashed().map(|(_, key, value)| (key, value))
+    }
+}
+
+// derive would needlessly require K: Clone and V: Clone
+impl<K, V> Clone for Iter<'_, K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+/// Borrowing iterator that also yields each entry's stored hash
+pub(crate) struct IterHashed<'a, K, V>(Iter<'a, K, V>);
+
+impl<'a, K, V> Iterator for IterHashed<'a, K, V> {
+    type Item = (u64, &'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_hashed()
+    }
+}
+
+pub(crate) struct IterMut<'a, K, V> {
+    entries: std::slice::IterMut<'a, Option<(u64, K, V)>>,
+}
+
+impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (_, key, value) = self.entries.next()?.as_mut()?;
+        Some((key, value))
+    }
+}
+
+pub(crate) struct IntoIter<K, V> {
+    entries: std::array::IntoIter<Option<(u64, K, V)>, CAPACITY>,
+}
+
+impl<K, V> IntoIter<K, V> {
+    /// Like `next`, but also hands back the hash stored with the entry
+    fn next_hashed(&mut self) -> Option<(u64, K, V)> {
+        self.entries.next().flatten()
+    }
+}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_hashed().map(|(_, key, value)| (key, value))
+    }
+}
+
+/// By value iterator that also yields each entry's stored hash
+pub(crate) struct IntoIterHashed<K, V>(IntoIter<K, V>);
+
+impl<K, V> Iterator for IntoIterHashed<K, V> {
+    type Item = (u64, K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_hashed()
+    }
+}
+
+impl<K, V> IntoIterator for InlineMap<K, V> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter {
+            // by value, which `.into_iter()` on an array isn't in edition 2018
+            entries: IntoIterator::into_iter(self.entries),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full() -> InlineMap<u64, u64> {
+        let mut map = InlineMap::new();
+        for i in 0..CAPACITY as u64 {
+            assert_eq!(map.insert(i, i, i * 10), None);
+        }
+        map
+    }
+
+    #[test]
+    fn insert_keeps_the_stored_key() {
+        let mut map = full();
+        assert_eq!(map.insert(9, 2, 200), Some(20));
+        assert_eq!(map.len(), CAPACITY);
+        assert_eq!(map.find(2, |&k| k == 2), Some((&2, &200)));
+        // the hash the key went in with is kept too
+        assert_eq!(map.find(9, |&k| k == 2), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "inline map full")]
+    fn insert_past_capacity_panics() {
+        full().insert(9, 9, 9);
+    }
+
+    #[test]
+    fn remove_keeps_the_rest_in_order() {
+        let mut map = full();
+        assert_eq!(map.remove_entry(&1, &mut Probes::default()), Some((1, 10)));
+        assert_eq!(map.remove_entry(&1, &mut Probes::default()), None);
+        assert_eq!(map.insert(7, 7, 70), None);
+
+        let keys: Vec<_> = map.iter().map(|(&k, _)| k).collect();
+        assert_eq!(keys, [0, 2, 3, 7]);
+        let hashes: Vec<_> = map.into_iter_hashed().map(|(h, _, _)| h).collect();
+        assert_eq!(hashes, [0, 2, 3, 7]);
+    }
+
+    #[test]
+    fn remove_marked_removes_in_order() {
+        let mut map = full();
+        let (keep, _) = map.mark(&mut |&k, _| ControlFlow::Continue(k % 2 == 1));
+        assert_eq!(keep, [false, true, false, true]);
+
+        let mut len = map.len();
+        let mut removed = Vec::new();
+        map.remove_marked(keep.iter().copied(), &mut len, |k, v| removed.push((k, v)));
+        assert_eq!(removed, [(0, 0), (2, 20)]);
+        assert_eq!(len, 2);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.iter().collect::<Vec<_>>(), [(&1, &10), (&3, &30)]);
+    }
+}
@@ -1,4 +1,7 @@
 mod avl_tree;
+pub mod fixed_state;
 pub mod hashmap;
+mod inline_map;
 mod linked_list;
+mod probes;
 mod tree_vec;
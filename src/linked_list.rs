@@ -1,13 +1,21 @@
 use std::borrow::Borrow;
 use std::mem;
+use std::ptr;
+
+use crate::probes::Probes;
 
 #[derive(Debug)]
 struct Node<K, V> {
+    /// Kept so the entry can move buckets without hashing the key again
+    hash: u64,
     key: K,
     value: V,
     next: Option<Box<Node<K, V>>>,
 }
 
+/// Entries are kept sorted by hash, equal hashes in the order they went in.
+/// `insert` stops looking for a duplicate at the first larger hash, so every
+/// way of building a list has to keep that order.
 #[derive(Debug)]
 pub(crate) struct LinkedList<K, V> {
     head: Option<Box<Node<K, V>>>,
@@ -21,41 +29,123 @@ impl<K, V> LinkedList<K, V> {
     pub fn is_empty(&self) -> bool {
         self.head.is_none()
     }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            next: self.head.as_deref(),
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut {
+            next: self.head.as_deref_mut(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        let mut len = 0;
+        let mut curr_opt = self.head.as_ref();
+        while let Some(curr) = curr_opt {
+            len += 1;
+            curr_opt = curr.next.as_ref();
+        }
+
+        len
+    }
+
+    /// Builds the list in one pass, keeping the order of `entries`, which
+    /// must already be sorted by hash. The keys must already be unique since
+    /// no duplicate check is done.
+    pub fn from_unique_sorted(entries: Vec<(u64, K, V)>) -> Self {
+        debug_assert!(
+            entries.windows(2).all(|pair| pair[0].0 <= pair[1].0),
+            "list entries not sorted by hash"
+        );
+        let mut head = None;
+        for (hash, key, value) in entries.into_iter().rev() {
+            head = Some(Box::new(Node {
+                hash,
+                key,
+                value,
+                next: head,
+            }));
+        }
+
+        Self { head }
+    }
 }
 
 impl<K, V> LinkedList<K, V>
 where
     K: Eq,
 {
-    pub fn insert(&mut self, key: K, mut value: V) -> Option<V> {
+    /// Inserts after every entry whose hash isn't greater, so the list
+    /// stays sorted by hash with equal hashes in insertion order
+    pub fn insert(&mut self, hash: u64, key: K, value: V) -> Option<V> {
+        let mut link = &mut self.head;
+        while link.as_ref().is_some_and(|node| node.hash <= hash) {
+            let node = link.as_mut().unwrap();
+            if node.hash == hash && node.key == key {
+                return Some(mem::replace(&mut node.value, value));
+            }
+            link = &mut node.next;
+        }
+
+        let next = link.take();
+        *link = Some(Box::new(Node {
+            hash,
+            key,
+            value,
+            next,
+        }));
+
+        None
+    }
+
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, hash: u64, key: K, f: F) -> &mut V {
+        if self.get(&key, &mut Probes::default()).is_some() {
+            return self.get_mut(&key, &mut Probes::default()).unwrap();
+        }
+
+        // same spot insert would pick
+        let mut link = &mut self.head;
+        while link.as_ref().is_some_and(|node| node.hash <= hash) {
+            link = &mut link.as_mut().unwrap().next;
+        }
+        let next = link.take();
+        let node = link.insert(Box::new(Node {
+            hash,
+            key,
+            value: f(),
+            next,
+        }));
+        &mut node.value
+    }
+
+    /// Swaps `key` in for the equal key stored in the list, returning the
+    /// stored one
+    pub fn replace_key(&mut self, key: K) -> Option<K> {
         let mut option = &mut self.head;
 
         while let Some(ref mut current) = option {
             if current.key == key {
-                mem::swap(&mut current.value, &mut value);
-                return Some(value);
+                return Some(mem::replace(&mut current.key, key));
             }
 
             option = &mut current.next;
         }
 
-        // We didn't find it in the list, so insert it at head
-        self.head = Some(Box::new(Node {
-            key,
-            value,
-            next: self.head.take(),
-        }));
-
         None
     }
 
-    pub fn get_key_value<Q: ?Sized>(&self, key: &Q) -> Option<(&K, &V)>
+    pub fn get_key_value<Q: ?Sized>(&self, key: &Q, probes: &mut Probes) -> Option<(&K, &V)>
     where
         K: Borrow<Q>,
         Q: Eq,
     {
         let mut curr_opt = self.head.as_ref();
         while let Some(ref curr) = curr_opt {
+            probes.step();
             if curr.key.borrow() == key {
                 return Some((&curr.key, &curr.value));
             }
@@ -65,7 +155,38 @@ where
         None
     }
 
-    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+    pub fn get<Q: ?Sized>(&self, key: &Q, probes: &mut Probes) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Eq,
+    {
+        let mut curr_opt = self.head.as_ref();
+        while let Some(curr) = curr_opt {
+            probes.step();
+            if curr.key.borrow() == key {
+                return Some(&curr.value);
+            }
+            curr_opt = curr.next.as_ref();
+        }
+
+        None
+    }
+
+    /// Finds the first entry stored under `hash` whose key satisfies
+    /// `is_match`. Entries with other hashes are skipped without calling it.
+    pub fn find<F: FnMut(&K) -> bool>(&self, hash: u64, mut is_match: F) -> Option<(&K, &V)> {
+        let mut curr_opt = self.head.as_ref();
+        while let Some(curr) = curr_opt {
+            if curr.hash == hash && is_match(&curr.key) {
+                return Some((&curr.key, &curr.value));
+            }
+            curr_opt = curr.next.as_ref();
+        }
+
+        None
+    }
+
+    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q, probes: &mut Probes) -> Option<&mut V>
     where
         K: Borrow<Q>,
         Q: Eq,
@@ -73,6 +194,7 @@ where
         let mut option = &mut self.head;
 
         while let Some(ref mut current) = option {
+            probes.step();
             if current.key.borrow() == key {
                 return Some(&mut current.value);
             }
@@ -83,35 +205,62 @@ where
         None
     }
 
-    pub fn remove_entry<Q: ?Sized>(&mut self, key: &Q) -> Option<(K, V)>
+    /// Removes `key`'s entry, also reporting whether the list is now empty
+    pub fn remove_entry_reporting_empty<Q: ?Sized>(
+        &mut self,
+        key: &Q,
+        probes: &mut Probes,
+    ) -> (Option<(K, V)>, bool)
     where
         K: Borrow<Q>,
         Q: Eq,
     {
+        self.remove_where(|node| node.key.borrow() == key, probes)
+    }
+
+    /// Removes the entry whose value is stored at `value`, telling nodes
+    /// apart by address so no key is compared, and reports whether the
+    /// list is now empty
+    pub fn remove_value_reporting_empty(&mut self, value: *const V) -> (Option<(K, V)>, bool) {
+        self.remove_where(|node| ptr::eq(&node.value, value), &mut Probes::default())
+    }
+
+    /// Unlinks the first node `is_match` picks. Only taking out a head with
+    /// nothing after it can empty the list, so that is where emptiness is
+    /// found out.
+    fn remove_where<F>(&mut self, mut is_match: F, probes: &mut Probes) -> (Option<(K, V)>, bool)
+    where
+        F: FnMut(&Node<K, V>) -> bool,
+    {
+        if self.head.is_some() {
+            probes.step();
+        }
         let head = match self.head.as_mut() {
-            Some(head) if head.key.borrow() == key => {
+            Some(head) if is_match(head) => {
                 let mut head = self.head.take().unwrap();
                 self.head = head.next.take();
-                return Some((head.key, head.value));
+                return (Some((head.key, head.value)), self.head.is_none());
             }
             Some(head) => head,
-            None => return None,
+            None => return (None, false),
         };
 
         let mut prev = head;
 
         // using complicated chains to avoid borrowing issues
         while prev.next.is_some() {
-            if prev.next.as_ref().unwrap().key.borrow() == key {
+            probes.step();
+            if is_match(prev.next.as_ref().unwrap()) {
                 let mut ret = prev.next.take().unwrap();
                 prev.next = ret.next.take();
-                return Some((ret.key, ret.value));
+                // the head is still there
+                return (Some((ret.key, ret.value)), false);
             }
 
             prev = prev.next.as_mut().unwrap();
         }
 
-        None
+        (None, false)
     }
 }
 
@@ -125,6 +274,61 @@ impl<K, V> Drop for LinkedList<K, V> {
     }
 }
 
+pub(crate) struct Iter<'a, K, V> {
+    next: Option<&'a Node<K, V>>,
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    /// Like `next`, but also hands back the hash stored with the entry
+    fn next_hashed(&mut self) -> Option<(u64, &'a K, &'a V)> {
+        self.next.map(|node| {
+            self.next = node.next.as_deref();
+            (node.hash, &node.key, &node.value)
+        })
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_hashed().map(|(_, key, value)| (key, value))
+    }
+}
+
+/// Borrowing iterator that also yields each entry's stored hash
+pub(crate) struct IterHashed<'a, K, V>(Iter<'a, K, V>);
+
+impl<'a, K, V> Iterator for IterHashed<'a, K, V> {
+    type Item = (u64, &'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_hashed()
+    }
+}
+
+// derive would needlessly require K: Clone and V: Clone
+impl<K, V> Clone for Iter<'_, K, V> {
+    fn clone(&self) -> Self {
+        Self { next: self.next }
+    }
+}
+
+pub(crate) struct IterMut<'a, K, V> {
+    next: Option<&'a mut Node<K, V>>,
+}
+
+impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next.take().map(|node| {
+            self.next = node.next.as_deref_mut();
+            (&node.key, &mut node.value)
+        })
+    }
+}
+
 pub(crate) struct IntoIter<K, V> {
     next: Option<Box<Node<K, V>>>,
 }
@@ -137,20 +341,38 @@ impl<K, V> IntoIter<K, V> {
     }
 }
 
-impl<K, V> Iterator for IntoIter<K, V> {
-    type Item = (K, V);
-
-    fn next(&mut self) -> Option<Self::Item> {
+impl<K, V> IntoIter<K, V> {
+    /// Like `next`, but also hands back the hash stored with the entry
+    fn next_hashed(&mut self) -> Option<(u64, K, V)> {
         match self.next.take() {
             Some(node) => {
                 self.next = node.next;
-                Some((node.key, node.value))
+                Some((node.hash, node.key, node.value))
             }
             None => None,
         }
     }
 }
 
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_hashed().map(|(_, key, value)| (key, value))
+    }
+}
+
+/// By value iterator that also yields each entry's stored hash
+pub(crate) struct IntoIterHashed<K, V>(IntoIter<K, V>);
+
+impl<K, V> Iterator for IntoIterHashed<K, V> {
+    type Item = (u64, K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_hashed()
+    }
+}
+
 impl<K, V> IntoIterator for LinkedList<K, V> {
     type Item = (K, V);
     type IntoIter = IntoIter<K, V>;
@@ -159,3 +381,88 @@ impl<K, V> IntoIterator for LinkedList<K, V> {
         IntoIter::new(self)
     }
 }
+
+impl<K, V> LinkedList<K, V> {
+    /// Consumes the list yielding `(hash, key, value)`, so the entries can be
+    /// moved elsewhere without hashing the keys again
+    pub fn into_iter_hashed(self) -> IntoIterHashed<K, V> {
+        IntoIterHashed(IntoIter::new(self))
+    }
+
+    /// Borrows every entry along with its hash, so the entries can be
+    /// copied elsewhere without hashing the keys again
+    pub fn iter_hashed(&self) -> IterHashed<'_, K, V> {
+        IterHashed(self.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get() {
+        let mut list = LinkedList::new();
+        for i in 0..10 {
+            list.insert(i as u64, i, i + 1);
+        }
+
+        for i in 0..10 {
+            assert_eq!(list.get(&i, &mut Probes::default()), Some(&(i + 1)));
+        }
+        assert_eq!(list.get(&10, &mut Probes::default()), None);
+    }
+
+    #[test]
+    fn zero_sized_values_take_no_space() {
+        #[allow(dead_code)]
+        struct KeyOnlyNode<K> {
+            hash: u64,
+            key: K,
+            next: Option<Box<KeyOnlyNode<K>>>,
+        }
+
+        assert_eq!(
+            mem::size_of::<Node<u64, ()>>(),
+            mem::size_of::<KeyOnlyNode<u64>>()
+        );
+    }
+
+    #[test]
+    fn remove_reports_empty_from_the_unlink() {
+        let mut list = LinkedList::new();
+        for i in 0..3 {
+            list.insert(i as u64, i, i);
+        }
+
+        // the middle, the head with entries after it, then the last one
+        for (key, empty) in [(1, false), (0, false), (2, true)] {
+            let (removed, now_empty) =
+                list.remove_entry_reporting_empty(&key, &mut Probes::default());
+            assert_eq!(removed, Some((key, key)));
+            assert_eq!(now_empty, empty);
+        }
+        assert_eq!(
+            list.remove_entry_reporting_empty(&0, &mut Probes::default()),
+            (None, false)
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "list entries not sorted by hash")]
+    fn from_unique_sorted_checks_the_order() {
+        LinkedList::from_unique_sorted(vec![(2, 2, 2), (1, 1, 1)]);
+    }
+
+    #[test]
+    fn from_unique_sorted() {
+        let entries: Vec<_> = (0..100).map(|i| (i as u64 * 7, i, i + 1)).collect();
+        let list = LinkedList::from_unique_sorted(entries.clone());
+
+        for i in 0..100 {
+            assert_eq!(list.get(&i, &mut Probes::default()), Some(&(i + 1)));
+        }
+        assert_eq!(list.into_iter_hashed().collect::<Vec<_>>(), entries);
+    }
+}
@@ -0,0 +1,68 @@
+#[cfg(feature = "probe-stats")]
+use std::cell::Cell;
+
+#[cfg(feature = "probe-stats")]
+use crate::hashmap::ProbeStats;
+
+/// Number of entries a single lookup compared its key against, counted as
+/// the lookup walks the bucket. Without the `probe-stats` feature it's zero
+/// sized and counting compiles away.
+#[derive(Debug, Default)]
+pub(crate) struct Probes {
+    #[cfg(feature = "probe-stats")]
+    count: u64,
+}
+
+impl Probes {
+    #[inline(always)]
+    pub fn step(&mut self) {
+        #[cfg(feature = "probe-stats")]
+        {
+            self.count += 1;
+        }
+    }
+}
+
+/// Running totals of a map built `with_probe_stats`. Zero sized without the
+/// `probe-stats` feature, so the default build has nothing to check.
+#[derive(Debug, Default)]
+pub(crate) struct ProbeTotals {
+    #[cfg(feature = "probe-stats")]
+    totals: Option<Cell<ProbeStats>>,
+}
+
+impl ProbeTotals {
+    #[cfg(feature = "probe-stats")]
+    pub fn enabled() -> Self {
+        Self {
+            totals: Some(Cell::default()),
+        }
+    }
+
+    #[cfg(feature = "probe-stats")]
+    pub fn get(&self) -> ProbeStats {
+        self.totals
+            .as_ref()
+            .map_or_else(ProbeStats::default, Cell::get)
+    }
+
+    /// Same setting, with nothing counted yet
+    pub fn cleared(&self) -> Self {
+        Self {
+            #[cfg(feature = "probe-stats")]
+            totals: self.totals.as_ref().map(|_| Cell::default()),
+        }
+    }
+
+    /// Adds a finished lookup to the totals, if they're being kept
+    #[inline(always)]
+    pub fn record(&self, _probes: Probes) {
+        #[cfg(feature = "probe-stats")]
+        if let Some(totals) = &self.totals {
+            let mut sum = totals.get();
+            sum.total_probes += _probes.count;
+            sum.total_lookups += 1;
+            totals.set(sum);
+        }
+    }
+}